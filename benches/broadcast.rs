@@ -0,0 +1,122 @@
+//! Broadcast throughput with many connected clients.
+//!
+//! Starts the server in this process, connects `CLIENTS` clients which all join
+//! the same room and measures how long it takes to deliver `MESSAGES` messages
+//! from one more client to all of them.
+//!
+//!     cargo bench --bench broadcast
+
+use futures::{SinkExt, StreamExt};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+use tagchat::protocol::{self, ClientFrame, ServerFrame};
+use tagchat::server::config::{Config, RateLimit};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LinesCodec};
+
+const CLIENTS: usize = 1000;
+const MESSAGES: usize = 1000;
+const ROOM: &str = "bench";
+
+async fn connect(addr: SocketAddr, name: String) -> Framed<TcpStream, LinesCodec> {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut lines = Framed::new(stream, LinesCodec::new());
+    lines
+        .send(protocol::encode(&ClientFrame::Login { name }))
+        .await
+        .unwrap();
+    lines
+        .send(protocol::encode(&ClientFrame::Join {
+            room: ROOM.into(),
+            since: None,
+        }))
+        .await
+        .unwrap();
+    lines
+}
+
+/// Read lines until `MESSAGES` chat messages arrive.
+async fn receive_all(mut lines: Framed<TcpStream, LinesCodec>) {
+    let mut received = 0;
+    while received < MESSAGES {
+        let line = lines.next().await.unwrap().unwrap();
+        if let Ok(ServerFrame::Message { .. }) = protocol::decode(&line) {
+            received += 1;
+        }
+    }
+}
+
+fn config(port: u16) -> Config {
+    let unlimited = RateLimit {
+        burst: MESSAGES as u32,
+        per_second: 1e9,
+    };
+    let mut config = Config {
+        bind: vec![Ipv4Addr::LOCALHOST.to_string()],
+        port,
+        stats_interval: 0,
+        ..Default::default()
+    };
+    config.limits.max_connections = 2 * CLIENTS;
+    config.limits.max_connections_per_ip = 2 * CLIENTS;
+    config.limits.outbound_queue = 2 * MESSAGES;
+    config.limits.connection_rate = unlimited.clone();
+    config.limits.account_rate = unlimited;
+    config
+}
+
+fn main() {
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        tokio::spawn(async move {
+            tagchat::server::run(
+                config(port),
+                move || Ok(config(port)),
+                std::future::pending(),
+            )
+            .await
+            .unwrap()
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut receivers = Vec::with_capacity(CLIENTS);
+        for i in 0..CLIENTS {
+            receivers.push(connect(addr, format!("client{}", i)).await);
+        }
+        let mut sender = connect(addr, "sender".into()).await;
+        // Let the server process the joins and the join notices.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let start = Instant::now();
+        let receivers: Vec<_> = receivers
+            .into_iter()
+            .map(|lines| tokio::spawn(receive_all(lines)))
+            .collect();
+        for i in 0..MESSAGES {
+            let frame = ClientFrame::Message {
+                room: ROOM.into(),
+                content: format!("message {}", i),
+            };
+            sender.send(protocol::encode(&frame)).await.unwrap();
+        }
+        futures::future::join_all(receivers).await;
+        let elapsed = start.elapsed();
+
+        let delivered = (CLIENTS * MESSAGES) as f64;
+        println!(
+            "{} clients, {} messages: {:.2?}, {:.0} messages/s, {:.0} deliveries/s",
+            CLIENTS,
+            MESSAGES,
+            elapsed,
+            MESSAGES as f64 / elapsed.as_secs_f64(),
+            delivered / elapsed.as_secs_f64(),
+        );
+    });
+}
@@ -1,70 +1,465 @@
+use crate::connection::{self, Event, ServerAddr};
+use crate::fragments::{Fragment, FragmentId, Fragments};
+use crate::protocol::{ClientFrame, ServerFrame, SharedTag, TagEdit};
+use crate::rules::{self, Rule, Rules};
+use crate::suggest::{Model, Suggestion};
+use crate::tags::{self, Filter, Match, SharedTags, TagId, TagSet, Tags};
+use crate::topics::{self, Topic};
 use clap::Parser;
-use egui::CollapsingHeader;
-use futures::future::join_all;
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::{SocketAddr, ToSocketAddrs};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::runtime::Builder;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
-struct Tag {
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct Message {
+    /// Id given by the server, missing in messages kept from before servers
+    /// gave them.
+    #[serde(default)]
+    id: Option<u64>,
+    content: String,
+    tags: TagSet,
+    sender: String,
+    room: String,
+    /// When the server got it, in seconds since the Unix epoch. Missing in
+    /// messages from older servers.
+    #[serde(default)]
+    sent: Option<u64>,
+}
+
+/// Where the search found a message.
+#[derive(Debug, Clone, PartialEq)]
+struct Hit {
+    room: String,
+    /// Index of the message in the room.
+    index: usize,
+}
+
+/// Search results with one of the tags.
+struct HitGroup {
     name: String,
-    color: egui::color::Rgba,
+    /// Whether the tag is shared by the room's members.
+    shared: bool,
+    hits: Vec<Hit>,
 }
 
-impl Default for Tag {
+/// A value computed from the messages and the tags, kept until they change
+/// or it's needed for something else.
+struct Cached<K, V> {
+    /// What the value was computed for, with `TagchatApp::revision` at the
+    /// time.
+    entry: Option<(K, u64, V)>,
+}
+
+impl<K, V> Default for Cached<K, V> {
     fn default() -> Self {
-        Tag {
-            name: "undefined".into(),
-            color: egui::Rgba::BLACK,
+        Self { entry: None }
+    }
+}
+
+impl<K: PartialEq, V> Cached<K, V> {
+    /// The value for `key`, computed again if `key` or `revision` changed.
+    fn get(&mut self, key: K, revision: u64, compute: impl FnOnce(&K) -> V) -> &V {
+        let fresh = matches!(&self.entry, Some((k, r, _)) if *k == key && *r == revision);
+        if !fresh {
+            let value = compute(&key);
+            self.entry = Some((key, revision, value));
         }
+        &self.entry.as_ref().expect("the value was just computed").2
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
-struct Message {
-    content: String,
-    tag: Tag,
-    sender: String,
-    room: String,
+/// Messages containing `pattern` in all the rooms, grouped by their tags. A
+/// message is in the group of every tag it has, the ones without tags make up
+/// the last group. There are no empty groups.
+fn group_hits(
+    state: &SerializedState,
+    shared_tags: &HashMap<String, SharedTags>,
+    pattern: &str,
+) -> Vec<HitGroup> {
+    let mut private: HashMap<TagId, Vec<Hit>> = HashMap::new();
+    let mut shared: BTreeMap<String, Vec<Hit>> = BTreeMap::new();
+    let mut untagged = vec![];
+
+    let mut rooms: Vec<_> = state.rooms.iter().collect();
+    rooms.sort_by(|a, b| a.0.cmp(b.0));
+    for (room, messages) in rooms {
+        let room_tags = shared_tags.get(room);
+        for (index, m) in messages.iter().enumerate() {
+            if !m.content.contains(pattern) {
+                continue;
+            }
+            let hit = Hit {
+                room: room.clone(),
+                index,
+            };
+            let mut tagged = false;
+            for id in &m.tags {
+                private.entry(*id).or_default().push(hit.clone());
+                tagged = true;
+            }
+            for tag in room_tags.into_iter().flat_map(|tags| tags.of(m.id)) {
+                let name = format!("{} in {}", tag.name, room);
+                shared.entry(name).or_default().push(hit.clone());
+                tagged = true;
+            }
+            if !tagged {
+                untagged.push(hit);
+            }
+        }
+    }
+
+    let mut groups: Vec<_> = state
+        .tags
+        .tree()
+        .into_iter()
+        .filter_map(|(id, _)| {
+            Some(HitGroup {
+                name: state.tags.path(id),
+                shared: false,
+                hits: private.remove(&id)?,
+            })
+        })
+        .collect();
+    groups.extend(shared.into_iter().map(|(name, hits)| HitGroup {
+        name,
+        shared: true,
+        hits,
+    }));
+    if !untagged.is_empty() {
+        groups.push(HitGroup {
+            name: "No tags".to_string(),
+            shared: false,
+            hits: untagged,
+        });
+    }
+    groups
+}
+
+/// Something in the timeline of a tag.
+enum Moment {
+    Fragment(Fragment),
+    Message(Hit),
+}
+
+/// Messages and fragments from all the rooms which have a tag, or one nested
+/// in it, in the order they were sent. Fragments come right before their
+/// first message, messages without the time come first.
+fn tag_timeline(state: &SerializedState, tag: TagId) -> Vec<Moment> {
+    let filter = Filter {
+        tags: TagSet::from([tag]),
+        mode: Match::Any,
+    };
+    // Sorted by when they were sent, then by where they are.
+    let mut moments = vec![];
+    for (room, messages) in &state.rooms {
+        for (index, m) in messages.iter().enumerate() {
+            if filter.matches(&state.tags, &m.tags) {
+                let hit = Hit {
+                    room: room.clone(),
+                    index,
+                };
+                moments.push(((m.sent, room.clone(), index, 1), Moment::Message(hit)));
+            }
+        }
+    }
+    for fragment in state.fragments.iter() {
+        if filter.matches(&state.tags, &fragment.tags) {
+            let messages = state
+                .rooms
+                .get(&fragment.room)
+                .map_or(&[][..], Vec::as_slice);
+            let index = messages.iter().position(|m| fragment.contains(m.id));
+            let sent = index.and_then(|index| messages[index].sent);
+            let key = (sent, fragment.room.clone(), index.unwrap_or(0), 0);
+            moments.push((key, Moment::Fragment(fragment.clone())));
+        }
+    }
+    moments.sort_by(|a, b| a.0.cmp(&b.0));
+    moments.into_iter().map(|(_, moment)| moment).collect()
+}
+
+/// Show tags as small buttons outlined with their colors.
+fn tag_chips(ui: &mut egui::Ui, tags: &Tags, ids: &TagSet) {
+    ui.horizontal(|ui| {
+        for tag in tags.of(ids) {
+            ui.add(
+                egui::Button::new(egui::RichText::new(tags.path(tag.id)).small())
+                    .stroke(egui::Stroke::new(2., tag.color)),
+            );
+        }
+    });
+}
+
+/// Show the shared tags of a message, in italics to tell them apart from the
+/// private ones.
+fn shared_tag_chips<'a>(ui: &mut egui::Ui, tags: impl Iterator<Item = &'a SharedTag>) {
+    ui.horizontal(|ui| {
+        for tag in tags {
+            ui.add(
+                egui::Button::new(egui::RichText::new(&tag.name).small().italics())
+                    .stroke(egui::Stroke::new(2., tags::shared_color(tag))),
+            )
+            .on_hover_text(format!("Shared, created by {}", tag.owner));
+        }
+    });
+}
+
+/// Color of a fragment's bracket, the one of its first tag.
+fn fragment_color(tags: &Tags, fragment: &Fragment) -> egui::Color32 {
+    tags.of(&fragment.tags)
+        .next()
+        .map_or(egui::Color32::GRAY, |tag| tag.color.into())
+}
+
+/// Show a checkbox for every tag, nested ones indented under their parents.
+/// Returns the tag the user checked or unchecked.
+fn tag_checkboxes(
+    ui: &mut egui::Ui,
+    tags: &Tags,
+    checked: impl Fn(TagId) -> bool,
+) -> Option<(TagId, bool)> {
+    let mut toggled = None;
+    for (id, depth) in tags.tree() {
+        let name = tags.get(id).map_or("", |tag| tag.name.as_str());
+        ui.horizontal(|ui| {
+            ui.add_space(16. * depth as f32);
+            let mut on = checked(id);
+            if ui.checkbox(&mut on, name).clicked() {
+                toggled = Some((id, on));
+            }
+        });
+    }
+    toggled
 }
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap(short, long, parse(try_from_str = parse_addr))]
-    server_addr: SocketAddr,
+    server_addr: ServerAddr,
 
     #[clap(short, long)]
     name: String,
+
+    /// Certificate of the server to trust over TLS or QUIC, e.g. the
+    /// `quic-cert.pem` it generated in its data directory.
+    #[clap(long)]
+    cert: Option<std::path::PathBuf>,
+}
+
+impl Args {
+    /// Address of the server, with the certificate to trust over TLS or QUIC.
+    fn server_addr(&self) -> ServerAddr {
+        if let ServerAddr::Tls { addr, host, .. } = &self.server_addr {
+            return ServerAddr::Tls {
+                addr: *addr,
+                host: host.clone(),
+                cert: self.cert.clone(),
+            };
+        }
+        #[cfg(feature = "quic")]
+        if let ServerAddr::Quic { addr, host, .. } = &self.server_addr {
+            return ServerAddr::Quic {
+                addr: *addr,
+                host: host.clone(),
+                cert: self.cert.clone(),
+            };
+        }
+        self.server_addr.clone()
+    }
 }
 
-fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+fn resolve(s: &str) -> Result<SocketAddr, String> {
     s.to_socket_addrs()
         .map_err(|e| e.to_string())
         .and_then(|mut iter| iter.next().ok_or_else(|| "No address found".to_string()))
 }
 
+/// The host part of `host:port`, without the brackets around IPv6 addresses.
+fn host(addr: &str) -> String {
+    addr.rsplit_once(':')
+        .map_or(addr, |(host, _)| host)
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_string()
+}
+
+/// A host and port, `unix:` followed by the path of the server's socket or
+/// `tls:` or `quic:` followed by a host and port.
+fn parse_addr(s: &str) -> Result<ServerAddr, String> {
+    if let Some(addr) = s.strip_prefix("tls:") {
+        return Ok(ServerAddr::Tls {
+            addr: resolve(addr)?,
+            host: host(addr),
+            cert: None,
+        });
+    }
+    if let Some(path) = s.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return Ok(ServerAddr::Unix(path.into()));
+        #[cfg(not(unix))]
+        return Err(format!("Unix sockets are not supported here: {}", path));
+    }
+    if let Some(addr) = s.strip_prefix("quic:") {
+        #[cfg(feature = "quic")]
+        return Ok(ServerAddr::Quic {
+            addr: resolve(addr)?,
+            host: host(addr),
+            cert: None,
+        });
+        #[cfg(not(feature = "quic"))]
+        return Err(format!("The client was built without QUIC: {}", addr));
+    }
+    resolve(s).map(ServerAddr::Tcp)
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 #[serde(default)]
 struct SerializedState {
     rooms: HashMap<String, Vec<Message>>,
     // messages: Vec<Message>,
-    tags: Vec<Tag>,
+    tags: Tags,
+    fragments: Fragments,
+    rules: Rules,
+    /// Tags are suggested only when the model is at least this sure of them.
+    suggestion_threshold: f32,
 }
 
 impl Default for SerializedState {
     fn default() -> Self {
         let mut rooms = HashMap::new();
         rooms.insert("Wspólny".to_string(), vec![]);
-        let s = Self {
+
+        Self {
             rooms,
-            tags: vec![Default::default()],
+            tags: Default::default(),
+            fragments: Default::default(),
+            rules: Default::default(),
+            suggestion_threshold: 0.6,
+        }
+    }
+}
+
+impl SerializedState {
+    /// The saved state, in either format.
+    fn load(storage: &dyn eframe::Storage) -> Option<Self> {
+        eframe::get_value(storage, eframe::APP_KEY)
+            .or_else(|| eframe::get_value::<LegacyState>(storage, eframe::APP_KEY).map(Into::into))
+    }
+
+    /// Delete a tag, taking it off its messages and fragments.
+    fn delete_tag(&mut self, id: TagId) {
+        if self.tags.remove(id) {
+            for message in self.rooms.values_mut().flatten() {
+                message.tags.remove(&id);
+            }
+            self.fragments.remove_tag(id);
+            self.rules.remove_tag(id);
+        }
+    }
+
+    /// Train a model which suggests tags on the tags of all the messages.
+    fn train(&self) -> Model {
+        Model::train(
+            self.rooms
+                .values()
+                .flatten()
+                .map(|message| (message.content.as_str(), &message.tags)),
+        )
+    }
+
+    /// Tag suggested for a message, if the model is sure enough of it.
+    fn suggestion(&self, model: &Model, content: &str) -> Option<Suggestion> {
+        model.suggest(content).filter(|suggestion| {
+            suggestion.confidence >= self.suggestion_threshold
+                && self.tags.get(suggestion.tag).is_some()
+        })
+    }
+
+    /// Run the rules over all the messages, adding the tags they give.
+    fn apply_rules(&mut self) {
+        for message in self.rooms.values_mut().flatten() {
+            let tags = self
+                .rules
+                .tags(&message.content, &message.sender, &message.room);
+            message.tags.extend(tags);
+        }
+    }
+}
+
+/// Tag saved by versions which told tags apart by their name and color.
+#[derive(serde::Deserialize, PartialEq)]
+struct LegacyTag {
+    name: String,
+    color: egui::Rgba,
+}
+
+/// Message saved with its own copy of the tag.
+#[derive(serde::Deserialize)]
+struct LegacyMessage {
+    content: String,
+    tag: LegacyTag,
+    sender: String,
+    room: String,
+}
+
+/// State saved by versions without tag ids.
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+struct LegacyState {
+    rooms: HashMap<String, Vec<LegacyMessage>>,
+    tags: Vec<LegacyTag>,
+}
+
+impl From<LegacyState> for SerializedState {
+    fn from(legacy: LegacyState) -> Self {
+        let mut tags = Tags::default();
+        let undefined = LegacyTag {
+            name: "undefined".into(),
+            color: egui::Rgba::BLACK,
+        };
+        let mut ids = vec![(undefined, None)];
+        for tag in legacy.tags {
+            if !ids.iter().any(|(known, _)| *known == tag) {
+                let id = tags.add(tag.name.clone(), tag.color, None);
+                ids.push((tag, Some(id)));
+            }
+        }
+
+        // Copies of tags which were deleted are dropped, as they would be if
+        // the tags were deleted now.
+        let tags_of = |tag: &LegacyTag| -> TagSet {
+            ids.iter()
+                .find(|(known, _)| known == tag)
+                .and_then(|(_, id)| *id)
+                .into_iter()
+                .collect()
         };
-        return s;
+        let rooms = legacy
+            .rooms
+            .into_iter()
+            .map(|(room, messages)| {
+                let messages = messages
+                    .into_iter()
+                    .map(|message| Message {
+                        id: None,
+                        tags: tags_of(&message.tag),
+                        content: message.content,
+                        sender: message.sender,
+                        room: message.room,
+                        sent: None,
+                    })
+                    .collect();
+                (room, messages)
+            })
+            .collect();
+        SerializedState {
+            rooms,
+            tags,
+            fragments: Default::default(),
+            rules: Default::default(),
+            ..Default::default()
+        }
     }
 }
 
@@ -74,36 +469,103 @@ pub struct TagchatApp {
     name: String,
     write_msg: String,
     search_pattern: String,
-    current_tag: Tag,
+    /// Messages found with `search_pattern`.
+    search_results: Cached<String, Vec<HitGroup>>,
+    /// Changes whenever the messages, the tags or the fragments do, so that
+    /// what's cached is computed again.
+    revision: u64,
+    /// Search result the user jumped to, highlighted in the room.
+    found: Option<Hit>,
+    /// Whether to scroll to `found` in the next frame.
+    scroll_to_found: bool,
+    /// Shows only the messages with these tags.
+    tag_filter: Filter,
+    /// Tags of the messages the user sends.
+    current_tags: TagSet,
     current_room: String,
 
     new_room: String,
     new_tag_name: String,
     new_tag_color: [f32; 4],
-    delete_tag: Option<usize>,
+    delete_tag: Option<TagId>,
+    /// Tag being dragged in the list of tags.
+    dragged_tag: Option<TagId>,
+    /// Tag whose timeline is open.
+    timeline: Option<TagId>,
+    timeline_moments: Cached<TagId, Vec<Moment>>,
+    new_rule_field: rules::Field,
+    new_rule_pattern: String,
+    new_rule_tag: Option<TagId>,
+    /// Why the last rule couldn't be added.
+    rule_error: Option<String>,
     marked_messages: Option<(usize, usize)>,
+    new_fragment_title: String,
+    /// Fragment to scroll to, opened from the list of fragments.
+    open_fragment: Option<FragmentId>,
+    /// Tags shared by the members of each room. They aren't saved, the
+    /// server sends them after joining a room.
+    shared_tags: HashMap<String, SharedTags>,
+    /// Suggests tags, trained at the start and when the user asks for it.
+    model: Model,
+    /// Tags suggested for the messages of each room, by their ids. Computed
+    /// again once the model, the threshold or the tags change.
+    suggestions: HashMap<String, HashMap<u64, Option<Suggestion>>>,
+    new_shared_tag_name: String,
+    new_shared_tag_color: [f32; 4],
+    /// Fragments proposed in each room, until accepted or dismissed.
+    proposed_topics: HashMap<String, Vec<Topic>>,
+    /// Last notice from the server.
+    notice: Option<String>,
+    /// Announcements and the message of the day, shown until dismissed.
+    banners: Vec<String>,
+    /// Last message of the day, so that reconnecting doesn't show it again.
+    motd: Option<String>,
 
-    send: Sender<Message>,
-    recv: Receiver<Message>,
+    send: Sender<ClientFrame>,
+    recv: Receiver<Event>,
 }
 
 impl Default for TagchatApp {
     fn default() -> Self {
-        let (send, recv) = channel(1024);
+        let (send, _) = channel(1024);
+        let (_, recv) = channel(1024);
         Self {
             state: Default::default(),
 
             name: Default::default(),
             write_msg: Default::default(),
             search_pattern: Default::default(),
-            current_tag: Default::default(),
+            search_results: Default::default(),
+            revision: 0,
+            found: None,
+            scroll_to_found: false,
+            tag_filter: Default::default(),
+            current_tags: Default::default(),
             current_room: "Wspólny".to_string(),
 
             new_room: Default::default(),
             new_tag_name: Default::default(),
             new_tag_color: Default::default(),
             delete_tag: None,
+            dragged_tag: None,
+            timeline: None,
+            timeline_moments: Default::default(),
+            new_rule_field: Default::default(),
+            new_rule_pattern: Default::default(),
+            new_rule_tag: None,
+            rule_error: None,
             marked_messages: None,
+            new_fragment_title: Default::default(),
+            open_fragment: None,
+            shared_tags: Default::default(),
+            model: Default::default(),
+            suggestions: Default::default(),
+            new_shared_tag_name: Default::default(),
+            new_shared_tag_color: Default::default(),
+            proposed_topics: Default::default(),
+            notice: None,
+            banners: vec![],
+            motd: None,
 
             send,
             recv,
@@ -114,84 +576,74 @@ impl Default for TagchatApp {
 impl TagchatApp {
     /// Called once before the first frame.
     pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let (my_send, mut recv) = channel(1024);
+        let (my_send, recv) = channel(1024);
         let (send, my_recv) = channel(1024);
 
-        let rt = Builder::new_current_thread().enable_all().build().unwrap();
-
         let args: Args = Args::parse();
-        let name = args.name.clone();
-
-        let addr = args.server_addr.clone();
 
         let state: SerializedState = _cc
             .storage
-            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .and_then(SerializedState::load)
             .unwrap_or_default();
+        let model = state.train();
 
-        let context = _cc.egui_ctx.clone();
-        std::thread::spawn(move || {
-            rt.block_on(async move {
-                let stream = TcpStream::connect(addr).await;
-                let stream = stream.unwrap();
-                let (mut read, mut write) = tokio::io::split(stream);
-                write.write_all((name + "\r\n").as_bytes()).await.unwrap();
-
-                let write_to_server = tokio::spawn(async move {
-                    while let Some(Message { content, room, .. }) = recv.recv().await {
-                        let s = room.to_string() + ":" + &content + "\r\n";
-                        write.write_all((s).as_bytes()).await.unwrap();
-                    }
-                });
-
-                let mut buffer = vec![0; 1024];
-                let read_from_server = tokio::spawn(async move {
-                    loop {
-                        let n_read = read.read(&mut buffer).await.unwrap();
-                        if let Ok(raw_message) = String::from_utf8(buffer[..n_read].to_vec()) {
-                            if let Some((sender, room_content)) = raw_message.split_once(':') {
-                                if let Some((room, content)) = room_content.split_once(':') {
-                                    send.send(Message {
-                                        content: content.to_string(),
-                                        tag: Default::default(),
-                                        sender: sender.to_string(),
-                                        room: room.to_string(),
-                                    })
-                                    .await
-                                    .unwrap();
-                                    context.request_repaint();
-                                }
-                            }
-                        }
-                    }
-                });
-
-                join_all(vec![write_to_server, read_from_server]).await;
-            });
-        });
+        connection::spawn(
+            args.server_addr(),
+            args.name.clone(),
+            state
+                .rooms
+                .iter()
+                .map(|(room, messages)| (room.clone(), messages.iter().rev().find_map(|m| m.id)))
+                .collect(),
+            recv,
+            send,
+            _cc.egui_ctx.clone(),
+        );
 
         let current_room = "Wspólny".to_string();
-        let current_tag = state
+        let current_tags = state
             .rooms
             .get(&current_room.clone())
             .unwrap()
             .last()
-            .and_then(|m| Some(m.tag.clone()))
-            .or_else(|| Some(Default::default()))
-            .unwrap();
+            .map(|m| m.tags.clone())
+            .unwrap_or_default();
 
         Self {
             state,
             name: args.name.to_owned(),
             write_msg: "".to_owned(),
             search_pattern: "".to_owned(),
-            current_tag,
+            search_results: Default::default(),
+            revision: 0,
+            found: None,
+            scroll_to_found: false,
+            tag_filter: Default::default(),
+            current_tags,
             current_room,
             new_room: Default::default(),
             new_tag_name: Default::default(),
             new_tag_color: Default::default(),
             delete_tag: None,
+            dragged_tag: None,
+            timeline: None,
+            timeline_moments: Default::default(),
+            new_rule_field: Default::default(),
+            new_rule_pattern: Default::default(),
+            new_rule_tag: None,
+            rule_error: None,
             marked_messages: None,
+            new_fragment_title: Default::default(),
+            open_fragment: None,
+            shared_tags: Default::default(),
+            model,
+            suggestions: Default::default(),
+            new_shared_tag_name: Default::default(),
+            new_shared_tag_color: Default::default(),
+            proposed_topics: Default::default(),
+            notice: None,
+            banners: vec![],
+            motd: None,
 
             send: my_send,
             recv: my_recv,
@@ -210,13 +662,36 @@ impl eframe::App for TagchatApp {
             name,
             write_msg,
             search_pattern,
-            ref mut current_tag,
+            ref mut search_results,
+            ref mut revision,
+            ref mut found,
+            ref mut scroll_to_found,
+            ref mut tag_filter,
+            ref mut current_tags,
             ref mut current_room,
             ref mut new_room,
             ref mut new_tag_name,
             ref mut new_tag_color,
             ref mut delete_tag,
+            ref mut dragged_tag,
+            ref mut timeline,
+            ref mut timeline_moments,
+            ref mut new_rule_field,
+            ref mut new_rule_pattern,
+            ref mut new_rule_tag,
+            ref mut rule_error,
             ref mut marked_messages,
+            ref mut new_fragment_title,
+            ref mut open_fragment,
+            ref mut shared_tags,
+            ref mut model,
+            ref mut suggestions,
+            ref mut new_shared_tag_name,
+            ref mut new_shared_tag_color,
+            ref mut proposed_topics,
+            ref mut notice,
+            ref mut banners,
+            ref mut motd,
             send,
             recv,
         } = self;
@@ -228,6 +703,11 @@ impl eframe::App for TagchatApp {
                         frame.quit();
                     }
                 });
+
+                if let Some(text) = notice {
+                    ui.separator();
+                    ui.label(text.as_str());
+                }
             });
         });
 
@@ -240,34 +720,39 @@ impl eframe::App for TagchatApp {
                 if ui.add(egui::TextEdit::singleline(write_msg)).lost_focus()
                     && ui.input().key_pressed(egui::Key::Enter)
                 {
-                    let new_message = Message {
-                        content: write_msg.to_string(),
-                        tag: current_tag.clone(),
-                        sender: name.clone(),
+                    // The message shows up once the server sends it back.
+                    send.blocking_send(ClientFrame::Message {
                         room: current_room.clone(),
-                    };
-                    send.blocking_send(new_message.clone()).unwrap_or_default();
-                    state
-                        .rooms
-                        .get_mut(current_room)
-                        .unwrap()
-                        .push(new_message.clone());
+                        content: write_msg.to_string(),
+                    })
+                    .unwrap_or_default();
                     write_msg.clear();
                 }
 
-                ui.add(
-                    egui::Button::new(current_tag.name.clone())
-                        .stroke(egui::Stroke::new(3., current_tag.color)),
-                );
+                if current_tags.is_empty() {
+                    ui.label("No tags");
+                }
+                tag_chips(ui, &state.tags, current_tags);
+                let suggestion = state
+                    .suggestion(model, write_msg)
+                    .filter(|suggestion| !current_tags.contains(&suggestion.tag));
+                if let Some(Suggestion { tag, confidence }) = suggestion {
+                    let text = format!(
+                        "Suggested: {} ({:.0}%)",
+                        state.tags.path(tag),
+                        confidence * 100.
+                    );
+                    if ui.button(text).clicked() {
+                        current_tags.insert(tag);
+                    }
+                }
 
-                ui.menu_button("Change tag", |ui| {
+                ui.menu_button("Change tags", |ui| {
                     let sa: egui::ScrollArea = egui::ScrollArea::vertical().max_height(50.);
                     sa.show(ui, |ui| {
-                        if state.tags.iter().any(|tag| {
-                            ui.radio_value(current_tag, tag.clone(), tag.name.clone())
-                                .clicked()
-                        }) {
-                            ui.close_menu();
+                        let checked = |id| current_tags.contains(&id);
+                        if let Some((id, on)) = tag_checkboxes(ui, &state.tags, checked) {
+                            tags::toggle(current_tags, id, on);
                             ctx.request_repaint();
                         }
                     });
@@ -275,47 +760,175 @@ impl eframe::App for TagchatApp {
             });
         });
 
-        if let Ok(mut message) = recv.try_recv() {
-            if !state.rooms.contains_key(&message.room) {
-                state.rooms.insert(message.room.clone(), vec![]);
+        match recv.try_recv() {
+            Ok(Event::Frame(ServerFrame::Message {
+                id,
+                sender,
+                room,
+                content,
+                sent,
+            })) => {
+                let mut message = Message {
+                    id: Some(id),
+                    content,
+                    tags: TagSet::new(),
+                    sender,
+                    room,
+                    sent,
+                };
+                if !state.rooms.contains_key(&message.room) {
+                    state.rooms.insert(message.room.clone(), vec![]);
+                }
+                message.tags = state
+                    .rules
+                    .tags(&message.content, &message.sender, &message.room);
+                if message.sender == *name {
+                    message.tags.extend(current_tags.iter().copied());
+                }
+                // History replayed over QUIC can come after newer messages.
+                let messages = state.rooms.get_mut(&message.room).unwrap();
+                let at = messages
+                    .iter()
+                    .rposition(|m| m.id.map_or(true, |other| other < id))
+                    .map_or(0, |i| i + 1);
+                messages.insert(at, message);
+                *revision += 1;
             }
-            message.tag = state
-                .rooms
-                .get(&message.room)
-                .unwrap()
-                .last()
-                .and_then(|m| Some(m.tag.clone()))
-                .or_else(|| Some(Default::default()))
-                .unwrap();
-            state
-                .rooms
-                .get_mut(&message.room)
-                .unwrap()
-                .push(message.clone());
+            Ok(Event::Frame(ServerFrame::RoomTags { room, tags, tagged })) => {
+                shared_tags.insert(room, SharedTags { tags, tagged });
+                *revision += 1;
+            }
+            Ok(Event::Frame(ServerFrame::TagChanged { room, tag })) => {
+                shared_tags.entry(room).or_default().put(tag);
+                *revision += 1;
+            }
+            Ok(Event::Frame(ServerFrame::TagDeleted { room, id })) => {
+                shared_tags.entry(room).or_default().remove(id);
+                *revision += 1;
+            }
+            Ok(Event::Frame(ServerFrame::Tagged {
+                room,
+                id,
+                messages,
+                on,
+            })) => {
+                shared_tags
+                    .entry(room)
+                    .or_default()
+                    .assign(id, messages, on);
+                *revision += 1;
+            }
+            Ok(Event::Frame(ServerFrame::Notice { text })) => *notice = Some(text),
+            Ok(Event::Frame(ServerFrame::Motd { text })) => {
+                if motd.as_ref() != Some(&text) {
+                    banners.push(text.clone());
+                    *motd = Some(text);
+                }
+            }
+            Ok(Event::Frame(ServerFrame::Announcement { text })) => banners.push(text),
+            Ok(Event::Frame(ServerFrame::Direct { sender, content })) => {
+                banners.push(format!("{} (private): {}", sender, content))
+            }
+            Ok(Event::Connected) => *notice = Some("Connected to the server".into()),
+            Ok(Event::Disconnected(reason)) => {
+                *notice = Some(format!(
+                    "Disconnected from the server ({}), reconnecting…",
+                    reason
+                ))
+            }
+            Ok(Event::Frame(_)) | Err(_) => {}
         }
 
-        if let Some(tag_idx) = delete_tag {
-            state.tags.remove(*tag_idx);
-            *delete_tag = None;
+        if let Some(id) = delete_tag.take() {
+            state.delete_tag(id);
+            *revision += 1;
+            suggestions.clear();
+            current_tags.remove(&id);
+            tag_filter.tags.remove(&id);
+            if *timeline == Some(id) {
+                *timeline = None;
+            }
+            if *new_rule_tag == Some(id) {
+                *new_rule_tag = None;
+            }
         }
 
+        // Message picked from the search results or a timeline.
+        let mut jump: Option<Hit> = None;
+
         egui::SidePanel::left("left_panel").show(ctx, |ui| {
             ui.set_max_width(200.);
             egui::CollapsingHeader::new("Your tags")
                 .default_open(false)
                 .show(ui, |ui| {
-                    for (i, tag) in state.tags.iter().enumerate() {
-                        ui.add(
-                            egui::Button::new(tag.name.clone())
-                                .stroke(egui::Stroke::new(3., tag.color)),
-                        )
-                        .context_menu(|ui| {
+                    // Dragging a tag onto another one nests it there.
+                    let mut drop_on = None;
+                    let mut dropped = false;
+                    for (id, depth) in state.tags.tree() {
+                        let tag = state.tags.get_mut(id).unwrap();
+                        let response = ui
+                            .horizontal(|ui| {
+                                ui.add_space(16. * depth as f32);
+                                ui.add(
+                                    egui::Button::new(tag.name.clone())
+                                        .stroke(egui::Stroke::new(3., tag.color))
+                                        .sense(egui::Sense::click_and_drag()),
+                                )
+                            })
+                            .inner
+                            .on_hover_text("Click to see the timeline, drag to move");
+                        if response.clicked() {
+                            *timeline = Some(id);
+                        }
+                        if response.drag_started() {
+                            *dragged_tag = Some(id);
+                        }
+                        dropped |= response.drag_released();
+                        if dragged_tag.is_some_and(|dragged| dragged != id)
+                            && ui.rect_contains_pointer(response.rect)
+                        {
+                            drop_on = Some(Some(id));
+                            ui.painter().rect_stroke(
+                                response.rect.expand(2.),
+                                2.,
+                                ui.visuals().selection.stroke,
+                            );
+                        }
+                        response.context_menu(|ui| {
+                            // Messages refer to the tag by its id, so they
+                            // follow these changes.
+                            ui.horizontal(|ui| {
+                                ui.label("Name");
+                                if ui.text_edit_singleline(&mut tag.name).changed() {
+                                    *revision += 1;
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("Color");
+                                egui::widgets::color_picker::color_edit_button_rgba(
+                                    ui,
+                                    &mut tag.color,
+                                    egui::color_picker::Alpha::OnlyBlend,
+                                );
+                            });
                             if ui.button("Delete").clicked() {
-                                *delete_tag = Some(i);
+                                *delete_tag = Some(tag.id);
                                 ui.close_menu();
                             }
                         });
                     }
+                    if dragged_tag.is_some() {
+                        let response = ui.label("Drop here to move it to the top");
+                        if ui.rect_contains_pointer(response.rect) {
+                            drop_on = Some(None);
+                        }
+                    }
+                    if dropped {
+                        if let (Some(dragged), Some(parent)) = (dragged_tag.take(), drop_on) {
+                            state.tags.set_parent(dragged, parent);
+                            *revision += 1;
+                        }
+                    }
 
                     ui.horizontal(|ui| {
                         ui.label("Add new tag");
@@ -323,11 +936,12 @@ impl eframe::App for TagchatApp {
                         ui.color_edit_button_rgba_unmultiplied(new_tag_color);
 
                         if ui.button("Add").clicked() && !new_tag_name.is_empty() {
-                            let [r, g, b, a] = new_tag_color.clone();
-                            state.tags.push(Tag {
-                                name: new_tag_name.clone(),
-                                color: egui::Rgba::from_rgba_unmultiplied(r, g, b, a),
-                            });
+                            let [r, g, b, a] = *new_tag_color;
+                            state.tags.add_path(
+                                new_tag_name,
+                                egui::Rgba::from_rgba_unmultiplied(r, g, b, a),
+                            );
+                            *revision += 1;
 
                             *new_tag_name = Default::default();
                             *new_tag_color = Default::default();
@@ -335,8 +949,227 @@ impl eframe::App for TagchatApp {
                     });
                 });
 
+            egui::CollapsingHeader::new("Tagging rules")
+                .default_open(false)
+                .show(ui, |ui| {
+                    let mut delete = None;
+                    for (index, rule) in state.rules.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            let tag = state.tags.path(rule.tag);
+                            ui.label(format!("{} {} → {}", rule.field, rule.pattern, tag));
+                            if ui.small_button("✖").clicked() {
+                                delete = Some(index);
+                            }
+                        });
+                    }
+                    if let Some(index) = delete {
+                        state.rules.remove(index);
+                    }
+
+                    ui.horizontal(|ui| {
+                        egui::ComboBox::from_id_source("new_rule_field")
+                            .selected_text(new_rule_field.to_string())
+                            .show_ui(ui, |ui| {
+                                for field in [
+                                    rules::Field::Content,
+                                    rules::Field::Sender,
+                                    rules::Field::Room,
+                                ] {
+                                    ui.selectable_value(new_rule_field, field, field.to_string());
+                                }
+                            });
+                        ui.text_edit_singleline(new_rule_pattern);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("→");
+                        let selected = new_rule_tag.map_or(String::new(), |id| state.tags.path(id));
+                        egui::ComboBox::from_id_source("new_rule_tag")
+                            .selected_text(selected)
+                            .show_ui(ui, |ui| {
+                                for (id, _) in state.tags.tree() {
+                                    ui.selectable_value(
+                                        new_rule_tag,
+                                        Some(id),
+                                        state.tags.path(id),
+                                    );
+                                }
+                            });
+                        if ui.button("Add rule").clicked() && !new_rule_pattern.is_empty() {
+                            let rule = match *new_rule_tag {
+                                Some(tag) => {
+                                    Rule::new(*new_rule_field, new_rule_pattern.clone(), tag)
+                                        .map_err(|e| e.to_string())
+                                }
+                                None => Err("Pick a tag first".to_string()),
+                            };
+                            match rule {
+                                Ok(rule) => {
+                                    state.rules.add(rule);
+                                    new_rule_pattern.clear();
+                                    *rule_error = None;
+                                }
+                                Err(e) => *rule_error = Some(e),
+                            }
+                        }
+                    });
+                    if let Some(error) = rule_error {
+                        ui.colored_label(egui::Color32::RED, error.as_str());
+                    }
+
+                    // New messages get tagged as they come, this is for the
+                    // ones which are already there.
+                    if ui.button("Apply to all messages").clicked() {
+                        state.apply_rules();
+                        *revision += 1;
+                    }
+                });
+
+            egui::CollapsingHeader::new("Suggestions")
+                .default_open(false)
+                .show(ui, |ui| {
+                    let threshold = egui::Slider::new(&mut state.suggestion_threshold, 0.0..=1.0)
+                        .text("Confidence");
+                    if ui.add(threshold).changed() {
+                        suggestions.clear();
+                    }
+                    // The model learns from the tags the messages have now.
+                    if ui.button("Retrain").clicked() {
+                        *model = state.train();
+                        suggestions.clear();
+                    }
+                });
+
+            let room_tags = shared_tags.entry(current_room.clone()).or_default();
+            egui::CollapsingHeader::new("Room tags")
+                .default_open(false)
+                .show(ui, |ui| {
+                    // Only the owner of a tag can change it, the server
+                    // checks that too.
+                    for tag in &mut room_tags.tags {
+                        let response = ui
+                            .add(
+                                egui::Button::new(tag.name.clone())
+                                    .stroke(egui::Stroke::new(3., tags::shared_color(tag))),
+                            )
+                            .on_hover_text(format!("Created by {}", tag.owner));
+                        if tag.owner != *name {
+                            continue;
+                        }
+                        response.context_menu(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.label("Name");
+                                ui.text_edit_singleline(&mut tag.name);
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("Color");
+                                let mut color = tags::shared_color(tag);
+                                egui::widgets::color_picker::color_edit_button_rgba(
+                                    ui,
+                                    &mut color,
+                                    egui::color_picker::Alpha::OnlyBlend,
+                                );
+                                tag.color = tags::to_shared_color(color);
+                            });
+                            let edit = if ui.button("Save").clicked() {
+                                Some(TagEdit::Change {
+                                    id: tag.id,
+                                    name: tag.name.clone(),
+                                    color: tag.color,
+                                })
+                            } else if ui.button("Delete").clicked() {
+                                Some(TagEdit::Delete { id: tag.id })
+                            } else {
+                                None
+                            };
+                            if let Some(edit) = edit {
+                                send.blocking_send(ClientFrame::EditTags {
+                                    room: current_room.clone(),
+                                    edit,
+                                })
+                                .unwrap_or_default();
+                                ui.close_menu();
+                            }
+                        });
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.label("Add room tag");
+                        ui.text_edit_singleline(new_shared_tag_name);
+                        ui.color_edit_button_rgba_unmultiplied(new_shared_tag_color);
+
+                        if ui.button("Add").clicked() && !new_shared_tag_name.is_empty() {
+                            let [r, g, b, a] = *new_shared_tag_color;
+                            let color = egui::Rgba::from_rgba_unmultiplied(r, g, b, a);
+                            send.blocking_send(ClientFrame::EditTags {
+                                room: current_room.clone(),
+                                edit: TagEdit::Create {
+                                    name: new_shared_tag_name.clone(),
+                                    color: tags::to_shared_color(color),
+                                },
+                            })
+                            .unwrap_or_default();
+
+                            *new_shared_tag_name = Default::default();
+                            *new_shared_tag_color = Default::default();
+                        }
+                    });
+                });
+
             ui.label("Search: ");
             ui.text_edit_singleline(search_pattern);
+            if !search_pattern.is_empty() {
+                let groups = search_results.get(search_pattern.clone(), *revision, |pattern| {
+                    group_hits(state, shared_tags, pattern)
+                });
+                if groups.is_empty() {
+                    ui.label("Nothing found");
+                }
+                egui::ScrollArea::vertical()
+                    .id_source("search_results")
+                    .max_height(300.)
+                    .show(ui, |ui| {
+                        for group in groups {
+                            let mut text = egui::RichText::new(format!(
+                                "{} ({})",
+                                group.name,
+                                group.hits.len()
+                            ));
+                            if group.shared {
+                                text = text.italics();
+                            }
+                            egui::CollapsingHeader::new(text)
+                                .id_source((&group.name, group.shared))
+                                .default_open(true)
+                                .show(ui, |ui| {
+                                    if ui.small_button("Jump to the first one").clicked() {
+                                        jump = Some(group.hits[0].clone());
+                                    }
+                                    for hit in &group.hits {
+                                        let m = &state.rooms[&hit.room][hit.index];
+                                        let excerpt: String = m.content.chars().take(40).collect();
+                                        let selected = found.as_ref() == Some(hit);
+                                        let label =
+                                            format!("{} in {}: {}", m.sender, hit.room, excerpt);
+                                        if ui.selectable_label(selected, label).clicked() {
+                                            jump = Some(hit.clone());
+                                        }
+                                    }
+                                });
+                        }
+                    });
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("Messages with");
+                ui.radio_value(&mut tag_filter.mode, Match::Any, "any");
+                ui.radio_value(&mut tag_filter.mode, Match::All, "all");
+                ui.label("of the tags:");
+            });
+            let checked = |id| tag_filter.tags.contains(&id);
+            if let Some((id, on)) = tag_checkboxes(ui, &state.tags, checked) {
+                tags::toggle(&mut tag_filter.tags, id, on);
+                *marked_messages = None;
+            }
 
             egui::ComboBox::from_label("Select room")
                 .selected_text(format!("{:?}", current_room))
@@ -355,16 +1188,217 @@ impl eframe::App for TagchatApp {
                     && !state.rooms.contains_key(new_room)
                 {
                     state.rooms.insert(new_room.clone(), vec![]);
+                    send.blocking_send(ClientFrame::Join {
+                        room: new_room.clone(),
+                        since: None,
+                    })
+                    .unwrap_or_default();
                     new_room.clear();
                 }
             });
         });
 
+        if let Some(id) = *timeline {
+            let mut open = true;
+            egui::Window::new(format!("Timeline of {}", state.tags.path(id)))
+                .id(egui::Id::new("timeline"))
+                .open(&mut open)
+                .vscroll(true)
+                .show(ctx, |ui| {
+                    let moments =
+                        timeline_moments.get(id, *revision, |id| tag_timeline(state, *id));
+                    if moments.is_empty() {
+                        ui.label("Nothing has this tag yet");
+                    }
+                    for moment in moments {
+                        match moment {
+                            Moment::Fragment(fragment) => {
+                                let text = format!("{}: {}", fragment.room, fragment.title);
+                                let text = egui::RichText::new(text).strong();
+                                if ui.selectable_label(false, text).clicked() {
+                                    *current_room = fragment.room.clone();
+                                    *open_fragment = Some(fragment.id);
+                                    *marked_messages = None;
+                                }
+                            }
+                            Moment::Message(hit) => {
+                                let m = &state.rooms[&hit.room][hit.index];
+                                let text = format!("{}: {}: {}", hit.room, m.sender, m.content);
+                                if ui
+                                    .selectable_label(found.as_ref() == Some(hit), text)
+                                    .clicked()
+                                {
+                                    jump = Some(hit.clone());
+                                }
+                            }
+                        }
+                    }
+                });
+            if !open {
+                *timeline = None;
+            }
+        }
+
+        if let Some(hit) = jump {
+            // The message has to be shown, with the ones around it.
+            let m = &state.rooms[&hit.room][hit.index];
+            if !tag_filter.matches(&state.tags, &m.tags) {
+                tag_filter.tags.clear();
+            }
+            *current_room = hit.room.clone();
+            *found = Some(hit);
+            *scroll_to_found = true;
+            *marked_messages = None;
+        }
+
         egui::SidePanel::right("right_panel").show(ctx, |ui| {
             ui.set_min_width(200.);
             ui.label("Your friends");
         });
 
+        // Announcements, above the messages
+        if !banners.is_empty() {
+            egui::TopBottomPanel::top("banners").show(ctx, |ui| {
+                let mut dismissed = None;
+                for (idx, text) in banners.iter().enumerate() {
+                    egui::Frame::none()
+                        .fill(egui::Color32::from_rgb(255, 236, 179))
+                        .rounding(4.)
+                        .inner_margin(6.)
+                        .show(ui, |ui| {
+                            ui.horizontal(|ui| {
+                                ui.label(
+                                    egui::RichText::new(text)
+                                        .size(18.0)
+                                        .color(egui::Color32::BLACK),
+                                );
+                                ui.with_layout(egui::Layout::right_to_left(), |ui| {
+                                    if ui.small_button("✖").clicked() {
+                                        dismissed = Some(idx);
+                                    }
+                                });
+                            });
+                        });
+                }
+                if let Some(idx) = dismissed {
+                    banners.remove(idx);
+                }
+            });
+        }
+
+        egui::SidePanel::right("fragments_panel").show(ctx, |ui| {
+            ui.set_max_width(200.);
+            ui.heading("Fragments");
+            let mut delete = None;
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for fragment in state
+                    .fragments
+                    .iter_mut()
+                    .filter(|fragment| tag_filter.matches(&state.tags, &fragment.tags))
+                {
+                    egui::CollapsingHeader::new(format!("{} ({})", fragment.title, fragment.room))
+                        .id_source(fragment.id)
+                        .show(ui, |ui| {
+                            if ui.text_edit_singleline(&mut fragment.title).changed() {
+                                *revision += 1;
+                            }
+                            ui.text_edit_multiline(&mut fragment.note);
+                            tag_chips(ui, &state.tags, &fragment.tags);
+                            ui.menu_button("Change tags", |ui| {
+                                let checked = |id| fragment.tags.contains(&id);
+                                if let Some((id, on)) = tag_checkboxes(ui, &state.tags, checked) {
+                                    tags::toggle(&mut fragment.tags, id, on);
+                                    *revision += 1;
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                if ui.button("Open").clicked() {
+                                    *current_room = fragment.room.clone();
+                                    *open_fragment = Some(fragment.id);
+                                    *marked_messages = None;
+                                }
+                                if ui.button("Delete").clicked() {
+                                    delete = Some(fragment.id);
+                                }
+                            });
+                        });
+                }
+
+                ui.separator();
+                if ui
+                    .button("Find topics")
+                    .on_hover_text("Propose fragments where the conversation changes its topic")
+                    .clicked()
+                {
+                    let lines: Vec<_> = state.rooms[current_room]
+                        .iter()
+                        .filter_map(|m| {
+                            Some(topics::Line {
+                                id: m.id?,
+                                content: &m.content,
+                                sender: &m.sender,
+                                sent: m.sent,
+                            })
+                        })
+                        .collect();
+                    let mut proposals = topics::topics(&lines);
+                    // Fragments the user already has aren't proposed again.
+                    proposals.retain(|topic| {
+                        !state.fragments.in_room(current_room).any(|fragment| {
+                            fragment.first == topic.first && fragment.last == topic.last
+                        })
+                    });
+                    proposed_topics.insert(current_room.clone(), proposals);
+                }
+
+                let proposals = proposed_topics.entry(current_room.clone()).or_default();
+                let mut accepted = None;
+                let mut dismissed = None;
+                for (idx, topic) in proposals.iter_mut().enumerate() {
+                    egui::CollapsingHeader::new(format!(
+                        "{} ({} messages)",
+                        topic.title, topic.len
+                    ))
+                    .id_source(("topic", topic.first))
+                    .show(ui, |ui| {
+                        ui.text_edit_singleline(&mut topic.title);
+                        tag_chips(ui, &state.tags, &topic.tags);
+                        ui.menu_button("Change tags", |ui| {
+                            let checked = |id| topic.tags.contains(&id);
+                            if let Some((id, on)) = tag_checkboxes(ui, &state.tags, checked) {
+                                tags::toggle(&mut topic.tags, id, on);
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            if ui.button("Accept").clicked() {
+                                accepted = Some(idx);
+                            }
+                            if ui.button("Dismiss").clicked() {
+                                dismissed = Some(idx);
+                            }
+                        });
+                    });
+                }
+                if let Some(idx) = accepted {
+                    let topic = proposals.remove(idx);
+                    state.fragments.add(
+                        current_room.clone(),
+                        topic.first,
+                        topic.last,
+                        topic.title,
+                        topic.tags,
+                    );
+                    *revision += 1;
+                } else if let Some(idx) = dismissed {
+                    proposals.remove(idx);
+                }
+            });
+            if let Some(id) = delete {
+                state.fragments.remove(id);
+                *revision += 1;
+            }
+        });
+
         // messages window
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
@@ -372,104 +1406,373 @@ impl eframe::App for TagchatApp {
             sa.max_height(f32::INFINITY)
                 .stick_to_bottom()
                 .show(ui, |ui| {
-                    for (m_idx, m) in state.rooms[current_room]
-                        .clone()
+                    // Messages with their indices in the room.
+                    let messages: Vec<_> = state.rooms[current_room]
                         .iter()
-                        .filter(|m| m.content.contains(search_pattern.as_str()))
+                        .cloned()
                         .enumerate()
-                    {
+                        .filter(|(_, m)| tag_filter.matches(&state.tags, &m.tags))
+                        .collect();
+                    let fragments: Vec<Fragment> =
+                        state.fragments.in_room(current_room).cloned().collect();
+                    let room_tags = shared_tags.entry(current_room.clone()).or_default();
+                    let room_suggestions = suggestions.entry(current_room.clone()).or_default();
+                    // Fragments are shown as brackets in a gutter on the left,
+                    // each one in its own column.
+                    let gutter = 6. * fragments.len() as f32;
+                    let left = ui.max_rect().left();
+                    let mut tops = HashMap::new();
+
+                    for (m_idx, (index, m)) in messages.iter().enumerate() {
+                        let prev = m_idx.checked_sub(1).and_then(|i| messages[i].1.id);
+                        let next = messages.get(m_idx + 1).and_then(|(_, m)| m.id);
+
+                        for fragment in &fragments {
+                            if fragment.contains(m.id) && !fragment.contains(prev) {
+                                tops.insert(fragment.id, ui.cursor().top());
+                                let response = ui
+                                    .horizontal(|ui| {
+                                        ui.add_space(gutter);
+                                        ui.label(
+                                            egui::RichText::new(&fragment.title)
+                                                .strong()
+                                                .color(fragment_color(&state.tags, fragment)),
+                                        );
+                                        tag_chips(ui, &state.tags, &fragment.tags);
+                                    })
+                                    .response;
+                                if *open_fragment == Some(fragment.id) {
+                                    response.scroll_to_me(Some(egui::Align::TOP));
+                                }
+                            }
+                        }
+
                         let align = if m.sender.eq(name) {
                             egui::Align::RIGHT
                         } else {
                             egui::Align::LEFT
                         };
 
-                        ui.with_layout(egui::Layout::top_down(align), |ui| {
-                            let response = ui.add(
-                                egui::Button::new(egui::RichText::new(&m.content).size(23.0))
-                                    .stroke(egui::Stroke::new(
-                                        if let Some(true) = marked_messages
-                                            .clone()
-                                            .and_then(|(i, j)| Some(i <= m_idx && m_idx <= j))
-                                        {
-                                            6.
-                                        } else {
-                                            3.
-                                        },
-                                        m.tag.color,
-                                    )),
-                            );
-                            ui.add(egui::Label::new(egui::RichText::new(&m.sender).size(15.0)));
-
-                            if search_pattern.is_empty() {
-                                if response.clicked() {
-                                    if let Some(true) = marked_messages
-                                        .clone()
-                                        .and_then(|(i, j)| Some(m_idx == i && i == j))
-                                    {
-                                        *marked_messages = None;
-                                    } else {
+                        ui.horizontal(|ui| {
+                            ui.add_space(gutter);
+                            ui.with_layout(egui::Layout::top_down(align), |ui| {
+                                let mut bubble =
+                                    egui::Button::new(egui::RichText::new(&m.content).size(23.0))
+                                        .stroke(egui::Stroke::new(
+                                            if let Some(true) = (*marked_messages)
+                                                .map(|(i, j)| i <= m_idx && m_idx <= j)
+                                            {
+                                                6.
+                                            } else {
+                                                3.
+                                            },
+                                            state
+                                                .tags
+                                                .of(&m.tags)
+                                                .next()
+                                                .map(|tag| tag.color)
+                                                .or_else(|| {
+                                                    room_tags
+                                                        .of(m.id)
+                                                        .next()
+                                                        .map(tags::shared_color)
+                                                })
+                                                .unwrap_or(egui::Rgba::BLACK),
+                                        ));
+                                let is_found = found.as_ref().is_some_and(|hit| {
+                                    hit.room == *current_room && hit.index == *index
+                                });
+                                if is_found {
+                                    bubble = bubble.fill(ui.visuals().selection.bg_fill);
+                                }
+                                let response = ui.add(bubble);
+                                if is_found && *scroll_to_found {
+                                    // Centered, with the messages around it.
+                                    response.scroll_to_me(Some(egui::Align::Center));
+                                }
+                                tag_chips(ui, &state.tags, &m.tags);
+                                shared_tag_chips(ui, room_tags.of(m.id));
+                                let suggestion = (m.tags.is_empty() && m.sender != *name)
+                                    .then(|| match m.id {
+                                        Some(id) => *room_suggestions
+                                            .entry(id)
+                                            .or_insert_with(|| state.suggestion(model, &m.content)),
+                                        // Messages from older versions have no
+                                        // ids to remember them by.
+                                        None => state.suggestion(model, &m.content),
+                                    })
+                                    .flatten();
+                                if let Some(Suggestion { tag, confidence }) = suggestion {
+                                    let text = format!(
+                                        "Tag as {}? ({:.0}%)",
+                                        state.tags.path(tag),
+                                        confidence * 100.
+                                    );
+                                    if ui.small_button(text).clicked() {
+                                        let messages = state.rooms.get_mut(current_room).unwrap();
+                                        messages[*index].tags.insert(tag);
+                                        *revision += 1;
+                                    }
+                                }
+                                ui.add(egui::Label::new(egui::RichText::new(&m.sender).size(15.0)));
+
+                                // Marked messages are a range of all the room's
+                                // messages, so not while some are hidden.
+                                if tag_filter.is_empty() {
+                                    if response.clicked() {
                                         if let Some(true) =
-                                            marked_messages.clone().and_then(|(i, j)| Some(i == j))
+                                            (*marked_messages).map(|(i, j)| m_idx == i && i == j)
                                         {
-                                            let point = marked_messages.clone().unwrap().0;
-                                            *marked_messages =
-                                                Some((min(m_idx, point), max(m_idx, point)));
+                                            *marked_messages = None;
                                         } else {
-                                            *marked_messages = Some((m_idx, m_idx));
+                                            if let Some(true) =
+                                                (*marked_messages).map(|(i, j)| i == j)
+                                            {
+                                                let point = (*marked_messages).unwrap().0;
+                                                *marked_messages =
+                                                    Some((min(m_idx, point), max(m_idx, point)));
+                                            } else {
+                                                *marked_messages = Some((m_idx, m_idx));
+                                            }
                                         }
                                     }
-                                }
 
-                                if let Some((i, j)) = marked_messages.clone() {
-                                    if i <= m_idx && m_idx <= j {
-                                        response.context_menu(|ui| {
-                                            if ui.button("Delete").clicked() {
-                                                ui.close_menu();
-                                                state
-                                                    .rooms
-                                                    .get_mut(current_room)
-                                                    .unwrap()
-                                                    .drain(i..(j + 1));
-                                                *marked_messages = None;
-                                                ctx.request_repaint();
-                                            }
+                                    if let Some((i, j)) = *marked_messages {
+                                        if i <= m_idx && m_idx <= j {
+                                            response.context_menu(|ui| {
+                                                if ui.button("Delete").clicked() {
+                                                    ui.close_menu();
+                                                    state
+                                                        .rooms
+                                                        .get_mut(current_room)
+                                                        .unwrap()
+                                                        .drain(i..(j + 1));
+                                                    *revision += 1;
+                                                    *marked_messages = None;
+                                                    ctx.request_repaint();
+                                                }
 
-                                            egui::CollapsingHeader::new("Change tag")
-                                                .default_open(false)
-                                                .show(ui, |ui| {
-                                                    let mut chosen_tag: Tag = m.tag.clone();
-                                                    if state.tags.iter().any(|tag| {
-                                                        ui.radio_value(
-                                                            &mut chosen_tag,
-                                                            tag.clone(),
-                                                            tag.name.clone(),
-                                                        )
-                                                        .clicked()
-                                                    }) {
-                                                        ui.close_menu();
-                                                        let _ = &state
+                                                // Checked if every marked message
+                                                // has the tag.
+                                                egui::CollapsingHeader::new("Tags")
+                                                    .default_open(false)
+                                                    .show(ui, |ui| {
+                                                        let marked = &mut state
                                                             .rooms
                                                             .get_mut(current_room)
                                                             .unwrap()
-                                                            [i..(j + 1)]
-                                                            .iter_mut()
-                                                            .for_each(|m| {
-                                                                m.tag = chosen_tag.clone();
+                                                            [i..(j + 1)];
+                                                        let checked = |id| {
+                                                            marked
+                                                                .iter()
+                                                                .all(|m| m.tags.contains(&id))
+                                                        };
+                                                        if let Some((id, on)) =
+                                                            tag_checkboxes(ui, &state.tags, checked)
+                                                        {
+                                                            for m in marked.iter_mut() {
+                                                                tags::toggle(&mut m.tags, id, on);
+                                                            }
+                                                            *revision += 1;
+                                                            ctx.request_repaint();
+                                                        }
+                                                    });
+
+                                                // Changed once the server sends
+                                                // the change back.
+                                                egui::CollapsingHeader::new("Room tags")
+                                                    .default_open(false)
+                                                    .show(ui, |ui| {
+                                                        let marked = &state.rooms
+                                                            [current_room.as_str()]
+                                                            [i..(j + 1)];
+                                                        for tag in &room_tags.tags {
+                                                            let mut on = marked.iter().all(|m| {
+                                                                room_tags.has(tag.id, m.id)
                                                             });
+                                                            if ui
+                                                                .checkbox(&mut on, &tag.name)
+                                                                .clicked()
+                                                            {
+                                                                let edit = TagEdit::Assign {
+                                                                    id: tag.id,
+                                                                    messages: marked
+                                                                        .iter()
+                                                                        .filter_map(|m| m.id)
+                                                                        .collect(),
+                                                                    on,
+                                                                };
+                                                                send.blocking_send(
+                                                                    ClientFrame::EditTags {
+                                                                        room: current_room.clone(),
+                                                                        edit,
+                                                                    },
+                                                                )
+                                                                .unwrap_or_default();
+                                                            }
+                                                        }
+                                                    });
+
+                                                ui.horizontal(|ui| {
+                                                    ui.text_edit_singleline(new_fragment_title);
+                                                    let marked =
+                                                        &state.rooms[current_room.as_str()];
+                                                    let ids = marked[i].id.zip(marked[j].id);
+                                                    let save = ui
+                                                        .add_enabled(
+                                                            ids.is_some(),
+                                                            egui::Button::new("Save as fragment"),
+                                                        )
+                                                        .on_disabled_hover_text(
+                                                            "Old messages have no ids",
+                                                        );
+                                                    if let (true, Some((first, last))) =
+                                                        (save.clicked(), ids)
+                                                    {
+                                                        // The fragment gets the tags
+                                                        // all of its messages have.
+                                                        let tags = marked[i..(j + 1)]
+                                                            .iter()
+                                                            .map(|m| m.tags.clone())
+                                                            .reduce(|a, b| &a & &b)
+                                                            .unwrap_or_default();
+                                                        let title = match new_fragment_title.trim()
+                                                        {
+                                                            "" => "Fragment".to_string(),
+                                                            title => title.to_string(),
+                                                        };
+                                                        state.fragments.add(
+                                                            current_room.clone(),
+                                                            first,
+                                                            last,
+                                                            title,
+                                                            tags,
+                                                        );
+                                                        *revision += 1;
+                                                        new_fragment_title.clear();
                                                         *marked_messages = None;
-                                                        ctx.request_repaint();
+                                                        ui.close_menu();
                                                     }
                                                 });
-                                        });
+                                            });
+                                        }
                                     }
                                 }
-                            }
+                            });
                         });
 
+                        for (column, fragment) in fragments.iter().enumerate() {
+                            if fragment.contains(m.id) && !fragment.contains(next) {
+                                if let Some(top) = tops.remove(&fragment.id) {
+                                    let x = left + 2. + 6. * column as f32;
+                                    let bottom = ui.cursor().top();
+                                    let stroke = egui::Stroke::new(
+                                        2.,
+                                        fragment_color(&state.tags, fragment),
+                                    );
+                                    ui.painter().line_segment(
+                                        [egui::pos2(x, top), egui::pos2(x, bottom)],
+                                        stroke,
+                                    );
+                                    ui.painter().line_segment(
+                                        [egui::pos2(x, top), egui::pos2(x + 4., top)],
+                                        stroke,
+                                    );
+                                    ui.painter().line_segment(
+                                        [egui::pos2(x, bottom), egui::pos2(x + 4., bottom)],
+                                        stroke,
+                                    );
+                                }
+                            }
+                        }
+
                         ui.add_space(30.);
                     }
+                    // Scrolled to them if they're in the room.
+                    *open_fragment = None;
+                    *scroll_to_found = false;
                 });
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Storage holding one saved state.
+    struct Saved(String);
+
+    impl eframe::Storage for Saved {
+        fn get_string(&self, key: &str) -> Option<String> {
+            Some(self.0.clone()).filter(|_| key == eframe::APP_KEY)
+        }
+
+        fn set_string(&mut self, _key: &str, value: String) {
+            self.0 = value;
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    #[test]
+    fn state_without_tag_ids_is_loaded() {
+        // As the first version saved it: every message has a copy of its tag,
+        // `undefined` when it has no other one.
+        let saved = r#"(
+            rooms: {
+                "Wspólny": [
+                    (content: "it crashes", tag: (name: "bug", color: ((1.0, 0.0, 0.0, 1.0))),
+                        sender: "ala", room: "Wspólny"),
+                    (content: "hi", tag: (name: "undefined", color: ((0.0, 0.0, 0.0, 1.0))),
+                        sender: "ola", room: "Wspólny"),
+                    (content: "gone", tag: (name: "old", color: ((0.0, 1.0, 0.0, 1.0))),
+                        sender: "ola", room: "Wspólny"),
+                ],
+            },
+            tags: [
+                (name: "undefined", color: ((0.0, 0.0, 0.0, 1.0))),
+                (name: "bug", color: ((1.0, 0.0, 0.0, 1.0))),
+            ],
+        )"#;
+        let state = SerializedState::load(&Saved(saved.into())).unwrap();
+
+        let tags: Vec<_> = state.tags.tree().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(tags.len(), 1);
+        let bug = state.tags.get(tags[0]).unwrap();
+        assert_eq!(bug.name, "bug");
+        assert_eq!(
+            bug.color,
+            egui::Rgba::from_rgba_premultiplied(1., 0., 0., 1.)
+        );
+
+        let messages = &state.rooms["Wspólny"];
+        let tags_of: Vec<_> = messages.iter().map(|m| m.tags.clone()).collect();
+        assert_eq!(
+            tags_of,
+            [TagSet::from([bug.id]), TagSet::new(), TagSet::new()]
+        );
+        assert!(messages.iter().all(|m| m.id.is_none() && m.sent.is_none()));
+    }
+
+    #[test]
+    fn saved_state_is_loaded_again() {
+        let mut state = SerializedState::default();
+        let id = state.tags.add("bug".into(), egui::Rgba::RED, None);
+        state.rooms.get_mut("Wspólny").unwrap().push(Message {
+            id: Some(3),
+            content: "it crashes".into(),
+            tags: TagSet::from([id]),
+            sender: "ala".into(),
+            room: "Wspólny".into(),
+            sent: Some(1),
+        });
+        let mut storage = Saved(String::new());
+        eframe::set_value(&mut storage, eframe::APP_KEY, &state);
+
+        let loaded = SerializedState::load(&storage).unwrap();
+        let message = &loaded.rooms["Wspólny"][0];
+        assert_eq!((message.id, &message.tags), (Some(3), &TagSet::from([id])));
+        assert_eq!(loaded.tags.get(id).unwrap().name, "bug");
+    }
+}
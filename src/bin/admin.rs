@@ -0,0 +1,77 @@
+//! Command line tool for the operators of a running chat server.
+//!
+//! The server has to have an `[admin]` section with a token in its
+//! configuration. The token is passed with `--token` or in the
+//! `TAGCHAT_ADMIN_TOKEN` environment variable, e.g.
+//!
+//!     cargo run --bin tagchat-admin -- -s localhost:6142 -t secret peers
+//!     cargo run --bin tagchat-admin -- -s localhost:6142 -t secret kick ala
+//!     cargo run --bin tagchat-admin -- -s localhost:6142 -t secret announce "Restart at 12:00"
+//!     cargo run --bin tagchat-admin -- -s unix:/run/tagchat.sock -t secret stats
+//!
+//! Run with `--help` for the list of commands.
+
+#![warn(rust_2018_idioms)]
+
+use clap::Parser;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use tagchat::protocol::{self, AdminCommand, ClientFrame, ServerFrame};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Address of the server, or `unix:` followed by the path of its Unix
+    /// socket.
+    #[clap(short, long, default_value = "127.0.0.1:6142")]
+    server: String,
+
+    /// Token from the `[admin]` section of the server's configuration.
+    /// Defaults to the TAGCHAT_ADMIN_TOKEN environment variable.
+    #[clap(short, long)]
+    token: Option<String>,
+
+    #[clap(subcommand)]
+    command: AdminCommand,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let token = match args.token {
+        Some(token) => token,
+        None => std::env::var("TAGCHAT_ADMIN_TOKEN")
+            .map_err(|_| "no token, pass --token or set TAGCHAT_ADMIN_TOKEN")?,
+    };
+
+    let frame = ClientFrame::Admin {
+        token,
+        command: args.command,
+    };
+    #[cfg(unix)]
+    if let Some(path) = args.server.strip_prefix("unix:") {
+        return send(std::os::unix::net::UnixStream::connect(path)?, &frame);
+    }
+    send(TcpStream::connect(&args.server)?, &frame)
+}
+
+/// Send the command and print the reply.
+fn send(mut stream: impl Read + Write, frame: &ClientFrame) -> Result<(), Box<dyn Error>> {
+    writeln!(stream, "{}", protocol::encode(frame))?;
+
+    for line in BufReader::new(stream).lines() {
+        // Skip notices and pings sent to everyone.
+        if let Ok(ServerFrame::AdminReply { ok, text }) = protocol::decode(&line?) {
+            if ok {
+                print!("{}", text);
+                if !text.ends_with('\n') {
+                    println!();
+                }
+                return Ok(());
+            }
+            eprintln!("error: {}", text);
+            std::process::exit(1);
+        }
+    }
+    Err("the server closed the connection".into())
+}
@@ -1,250 +1,207 @@
-//! A chat server that broadcasts a message to all connections.
+//! The TagChat server.
 //!
-//! This example is explicitly more verbose than it has to be. This is to
-//! illustrate more concepts.
-//!
-//! A chat server for telnet clients. After a telnet client connects, the first
-//! line should contain the client's name. After that, all lines sent by a
-//! client are broadcasted to all other connected clients.
-//!
-//! Because the client is telnet, lines are delimited by "\r\n".
-//!
-//! You can test this out by running:
-//!
-//!     cargo run --example chat
-//!
-//! And then in another terminal run:
+//! The GUI client talks to it in JSON frames (see `tagchat::protocol`) over
+//! TCP, TLS, a Unix socket or QUIC. Someone typing over telnet sends their
+//! name first, then chats in plain text with `/` commands (see
+//! `tagchat::server::text`), and IRC clients can use the `[irc]` gateway.
+//! Messages go to everyone in a room, and the server keeps the recent ones
+//! for clients catching up.
 //!
+//!     cargo run --bin server -- -c server.example.toml
 //!     telnet localhost 6142
 //!
-//! You can run the `telnet` command in any number of additional windows.
+//! The listen addresses, data directory, limits and the rest of the settings
+//! are read from a TOML file passed with `--config` (see
+//! `tagchat::server::config`). Command line options override the file.
 //!
-//! You can run the second command in multiple windows and then chat between the
-//! two, seeing the messages from the other client as they're received. For all
-//! connected clients they'll all join the same room and see everyone else's
-//! messages.
+//! On SIGINT (Ctrl+C) or SIGTERM the server stops accepting connections, tells
+//! the clients it's going down, lets them receive the messages still queued
+//! for them and saves the history of the rooms in the data directory. On
+//! SIGHUP it reads the configuration again and applies what it can without
+//! a restart, including the log level.
+//!
+//! Every connection and room logs inside its own span, so the events carry
+//! the address, user and room they're about. With `--log-format json` each
+//! event is a JSON object on its own line, ready for a log collector.
 
 #![warn(rust_2018_idioms)]
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
-use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec};
-
 use clap::Parser;
-use futures::SinkExt;
-use std::collections::HashMap;
 use std::error::Error;
-use std::io;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use tagchat::server::config::{Config, ConfigError, LogFormat, Rotation, UnixSocket};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// Path to the TOML configuration file.
     #[clap(short, long)]
-    port: u16,
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
-    // Configure a `tracing` subscriber that logs traces emitted by the chat
-    // server.
-    tracing_subscriber::fmt()
-        // Filter what traces are displayed based on the RUST_LOG environment
-        // variable.
-        //
-        // Traces emitted by the example code will always be displayed. You
-        // can set `RUST_LOG=tokio=trace` to enable additional traces emitted by
-        // Tokio itself.
-        .with_env_filter(EnvFilter::from_default_env().add_directive("chat=info".parse()?))
-        // Log events when `tracing` spans are created, entered, exited, or
-        // closed. When Tokio's internal tracing support is enabled (as
-        // described above), this can be used to track the lifecycle of spawned
-        // tasks on the Tokio runtime.
-        .with_span_events(FmtSpan::FULL)
-        // Set this subscriber as the default, to collect all traces emitted by
-        // the program.
-        .init();
-
-    // Create the shared state. This is how all the peers communicate.
-    //
-    // The server task will hold a handle to this. For every new client, the
-    // `state` handle is cloned and passed into the task that processes the
-    // client connection.
-    let state = Arc::new(Mutex::new(Shared::new()));
+    config: Option<PathBuf>,
 
-    let args: Args = Args::parse();
-    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
+    /// Port for the listen addresses which don't specify their own.
+    #[clap(short, long)]
+    port: Option<u16>,
 
-    // Bind a TCP listener to the socket address.
-    //
-    // Note that this is the Tokio TcpListener, which is fully async.
-    let listener = TcpListener::bind(&addr).await?;
+    /// Address to listen on, optionally with a port, e.g. `0.0.0.0` or
+    /// `[::1]:7000`. Can be given many times, replaces `bind` from the
+    /// configuration file.
+    #[clap(short, long)]
+    bind: Vec<String>,
 
-    tracing::info!("server running on {}", addr);
+    /// Path of a Unix domain socket to listen on besides TCP.
+    #[clap(long)]
+    unix_socket: Option<PathBuf>,
 
-    loop {
-        // Asynchronously wait for an inbound TcpStream.
-        let (stream, addr) = listener.accept().await?;
+    /// Directory where the server keeps its persistent state.
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
 
-        // Clone a handle to the `Shared` state for the new connection.
-        let state = Arc::clone(&state);
+    /// One of off, error, warn, info, debug, trace.
+    #[clap(long)]
+    log_level: Option<String>,
 
-        // Spawn our handler to be run asynchronously.
-        tokio::spawn(async move {
-            tracing::debug!("accepted connection");
-            if let Err(e) = process(state, stream, addr).await {
-                tracing::info!("an error occurred; error = {:?}", e);
-            }
-        });
-    }
+    /// Format of the log lines.
+    #[clap(long, value_enum)]
+    log_format: Option<LogFormat>,
 }
 
-/// Shorthand for the transmit half of the message channel.
-type Tx = mpsc::UnboundedSender<String>;
+impl Args {
+    /// Build the configuration from the config file and the command line.
+    fn config(&self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
 
-/// Shorthand for the receive half of the message channel.
-type Rx = mpsc::UnboundedReceiver<String>;
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if !self.bind.is_empty() {
+            config.bind = self.bind.clone();
+        }
+        if let Some(path) = &self.unix_socket {
+            let mode = config
+                .unix_socket
+                .as_ref()
+                .map_or_else(UnixSocket::default_mode, |unix| unix.mode);
+            config.unix_socket = Some(UnixSocket {
+                path: path.clone(),
+                mode,
+            });
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
 
-/// Data that is shared between all peers in the chat server.
-///
-/// This is the set of `Tx` handles for all connected clients. Whenever a
-/// message is received from a client, it is broadcasted to all peers by
-/// iterating over the `peers` entries and sending a copy of the message on each
-/// `Tx`.
-struct Shared {
-    peers: HashMap<SocketAddr, Tx>,
+        config.validate()?;
+        Ok(config)
+    }
 }
 
-/// The state for each connected client.
-struct Peer {
-    /// The TCP socket wrapped with the `Lines` codec, defined below.
-    ///
-    /// This handles sending and receiving data on the socket. When using
-    /// `Lines`, we can work at the line level instead of having to manage the
-    /// raw byte operations.
-    lines: Framed<TcpStream, LinesCodec>,
-
-    /// Receive half of the message channel.
-    ///
-    /// This is used to receive messages from peers. When a message is received
-    /// off of this `Rx`, it will be written to the socket.
-    rx: Rx,
+/// Log the server's traces at `level`, and the rest as set by `RUST_LOG`.
+fn env_filter(level: &str) -> tracing_subscriber::EnvFilter {
+    // `Config::validate` checked the level.
+    let directive = |target| format!("{}={}", target, level).parse().unwrap();
+    tracing_subscriber::EnvFilter::from_default_env()
+        .add_directive(directive("tagchat"))
+        .add_directive(directive("server"))
 }
 
-impl Shared {
-    /// Create a new, empty, instance of `Shared`.
-    fn new() -> Self {
-        Shared {
-            peers: HashMap::new(),
-        }
-    }
+/// Complete once the process is asked to terminate.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    /// Send a `LineCodec` encoded message to every peer, except
-    /// for the sender.
-    async fn broadcast(&mut self, sender: SocketAddr, message: &str) {
-        for peer in self.peers.iter_mut() {
-            if *peer.0 != sender {
-                let _ = peer.1.send(message.into());
-            }
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
         }
     }
-}
-
-impl Peer {
-    /// Create a new instance of `Peer`.
-    async fn new(
-        state: Arc<Mutex<Shared>>,
-        lines: Framed<TcpStream, LinesCodec>,
-    ) -> io::Result<Peer> {
-        // Get the client socket address
-        let addr = lines.get_ref().peer_addr()?;
-
-        // Create a channel for this peer
-        let (tx, rx) = mpsc::unbounded_channel();
-
-        // Add an entry for this `Peer` in the shared state map.
-        state.lock().await.peers.insert(addr, tx);
-
-        Ok(Peer { lines, rx })
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
 
-/// Process an individual chat client
-async fn process(
-    state: Arc<Mutex<Shared>>,
-    stream: TcpStream,
-    addr: SocketAddr,
-) -> Result<(), Box<dyn Error>> {
-    let mut lines = Framed::new(stream, LinesCodec::new());
-
-    // Send a prompt to the client to enter their username.
-
-    // lines.send("Please enter your username:").await?;
-
-    // Read the first line from the `LineCodec` stream to get the username.
-    let username = match lines.next().await {
-        Some(Ok(line)) => line,
-        // We didn't get a line so we return early here.
-        _ => {
-            tracing::error!("Failed to get username from {}. Client disconnected.", addr);
-            return Ok(());
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    use tracing_appender::{non_blocking, rolling};
+    use tracing_subscriber::{fmt, fmt::format::FmtSpan, prelude::*, reload};
+
+    let args: Args = Args::parse();
+    let config = match args.config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(2);
         }
     };
 
-    // Register our peer with state which internally sets up some channels.
-    let mut peer = Peer::new(state.clone(), lines).await?;
-
-    // A client has connected, let's let everyone know.
-    {
-        let mut state = state.lock().await;
-        let msg = format!("{} has joined the chat", username);
-        tracing::info!("{}", msg);
-        state.broadcast(addr, &msg).await;
-    }
-
-    // Process incoming messages until our stream is exhausted by a disconnect.
-    loop {
-        tokio::select! {
-            // A message was received from a peer. Send it to the current user.
-            Some(msg) = peer.rx.recv() => {
-                peer.lines.send(&msg).await?;
-            }
-            result = peer.lines.next() => match result {
-                // A message was received from the current user, we should
-                // broadcast this message to the other users.
-                Some(Ok(msg)) => {
-                    let mut state = state.lock().await;
-                    let msg = format!("{}:{}", username, msg);
-
-                    state.broadcast(addr, &msg).await;
-                }
-                // An error occurred.
-                Some(Err(e)) => {
-                    tracing::error!(
-                        "an error occurred while processing messages for {}; error = {:?}",
-                        username,
-                        e
-                    );
-                }
-                // The stream has been exhausted.
-                None => break,
-            },
+    // Logs go to stdout or to a rotated file. Writing to the file happens on
+    // a background thread which flushes it when `_guard` is dropped.
+    let (writer, _guard) = match &config.log_file {
+        Some(file) => {
+            let rotation = match file.rotation {
+                Rotation::Minutely => rolling::Rotation::MINUTELY,
+                Rotation::Hourly => rolling::Rotation::HOURLY,
+                Rotation::Daily => rolling::Rotation::DAILY,
+                Rotation::Never => rolling::Rotation::NEVER,
+            };
+            non_blocking(rolling::RollingFileAppender::new(
+                rotation,
+                &file.directory,
+                &file.prefix,
+            ))
         }
-    }
+        None => non_blocking(std::io::stdout()),
+    };
 
-    // If this section is reached it means that the client was disconnected!
-    // Let's let everyone still connected know about it.
-    {
-        let mut state = state.lock().await;
-        state.peers.remove(&addr);
+    // Log events when `tracing` spans are created and closed, so the log shows
+    // when connections and rooms start and end.
+    let format = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(config.log_file.is_none())
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE);
+    let format = match config.log_format {
+        LogFormat::Text => format.boxed(),
+        LogFormat::Json => format.json().boxed(),
+    };
 
-        let msg = format!("{} has left the chat", username);
-        tracing::info!("{}", msg);
-        state.broadcast(addr, &msg).await;
-    }
+    let (filter, filter_handle) = reload::Layer::new(env_filter(&config.log_level));
+
+    // Configure a `tracing` subscriber that logs traces emitted by the chat
+    // server.
+    tracing_subscriber::registry()
+        // Filter what traces are displayed based on the RUST_LOG environment
+        // variable.
+        //
+        // Traces emitted by the server code will always be displayed at the
+        // configured level. You can set `RUST_LOG=tokio=trace` to enable
+        // additional traces emitted by Tokio itself.
+        //
+        // The filter sits behind a handle, so that reloading the configuration
+        // can change the level.
+        .with(filter)
+        .with(format)
+        // Set this subscriber as the default, to collect all traces emitted by
+        // the program.
+        .init();
 
-    Ok(())
+    // Operators can make the server read the configuration again.
+    let reload = move || {
+        let config = args.config()?;
+        if let Err(e) = filter_handle.reload(env_filter(&config.log_level)) {
+            tracing::error!(error = %e, "couldn't change the log level");
+        }
+        Ok(config)
+    };
+    tagchat::server::run(config, reload, shutdown_signal()).await
 }
@@ -0,0 +1,327 @@
+//! Talking to the server, on a thread separate from the GUI.
+//!
+//! The GUI sends `ClientFrame`s through a channel and gets `Event`s back. When
+//! the connection breaks, the server stops answering pings or announces that
+//! it's going down, the thread reconnects and joins the rooms again, asking
+//! for the messages it missed in the meantime.
+//!
+//! The server is reached over TCP, possibly with TLS, on the same host through
+//! its Unix socket or, with the `quic` feature, over QUIC. Over QUIC the history replayed on
+//! joining rooms comes on a stream of its own, so it can arrive after newer
+//! live messages.
+
+use crate::protocol::{self, ClientFrame, ServerFrame};
+use futures::{SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
+use tokio::runtime::Builder;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec, LinesCodecError};
+
+/// How often to ping the server, until it says how often it wants to be
+/// pinged.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long the server can stay silent before it is considered dead, until it
+/// says how long it waits for the client.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Longest pause between reconnection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What the connection passes on to the GUI.
+#[derive(Debug)]
+pub enum Event {
+    /// A frame from the server, other than the heartbeat.
+    Frame(ServerFrame),
+    Connected,
+    /// The connection broke for the given reason. Reconnecting is underway.
+    Disconnected(String),
+}
+
+type SessionError = Box<dyn Error + Send + Sync>;
+
+/// Where the server listens.
+#[derive(Debug, Clone)]
+pub enum ServerAddr {
+    Tcp(SocketAddr),
+    Tls {
+        addr: SocketAddr,
+        /// Name the server's certificate has to be issued for.
+        host: String,
+        /// Certificate to trust.
+        cert: Option<PathBuf>,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(feature = "quic")]
+    Quic {
+        addr: SocketAddr,
+        /// Name the server's certificate has to be issued for.
+        host: String,
+        /// Certificate to trust, e.g. the one the server generated.
+        cert: Option<PathBuf>,
+    },
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "{}", addr),
+            ServerAddr::Tls { addr, .. } => write!(f, "tls:{}", addr),
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(feature = "quic")]
+            ServerAddr::Quic { addr, .. } => write!(f, "quic:{}", addr),
+        }
+    }
+}
+
+/// Lines from the server, from all streams of the connection.
+type Lines = Pin<Box<dyn Stream<Item = Result<String, LinesCodecError>> + Send>>;
+
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Open a connection to the server.
+async fn connect(addr: &ServerAddr) -> io::Result<(Lines, Writer)> {
+    match addr {
+        ServerAddr::Tcp(addr) => {
+            let (read, write) = TcpStream::connect(addr).await?.into_split();
+            Ok((
+                Box::pin(FramedRead::new(read, LinesCodec::new())),
+                Box::new(write),
+            ))
+        }
+        ServerAddr::Tls { addr, host, cert } => {
+            let cert = cert.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS needs the server's certificate, pass --cert",
+                )
+            })?;
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots(cert)?)
+                .with_no_client_auth();
+            let name = ServerName::try_from(host.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let stream = TcpStream::connect(addr).await?;
+            let stream = TlsConnector::from(Arc::new(config))
+                .connect(name, stream)
+                .await?;
+            let (read, write) = tokio::io::split(stream);
+            Ok((
+                Box::pin(FramedRead::new(read, LinesCodec::new())),
+                Box::new(write),
+            ))
+        }
+        #[cfg(unix)]
+        ServerAddr::Unix(path) => {
+            let (read, write) = tokio::net::UnixStream::connect(path).await?.into_split();
+            Ok((
+                Box::pin(FramedRead::new(read, LinesCodec::new())),
+                Box::new(write),
+            ))
+        }
+        #[cfg(feature = "quic")]
+        ServerAddr::Quic { addr, host, cert } => {
+            let cert = cert.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "QUIC needs the server's certificate, pass --cert",
+                )
+            })?;
+            let connection = connect_quic(*addr, host, cert).await?;
+            let (write, read) = connection.open_bi().await?;
+            // The server opens a stream for the history once we're connected.
+            let history = futures::stream::once(async move { connection.accept_uni().await })
+                .filter_map(|stream| async move { stream.ok() })
+                .flat_map(|stream| FramedRead::new(stream, LinesCodec::new()));
+            let live = FramedRead::new(read, LinesCodec::new());
+            Ok((
+                Box::pin(futures::stream::select(live, history)),
+                Box::new(write),
+            ))
+        }
+    }
+}
+
+fn invalid(e: &dyn fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// The certificates from a PEM file, to trust when connecting over TLS or
+/// QUIC.
+fn roots(cert: &Path) -> io::Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(cert).map_err(|e| invalid(&e))? {
+        roots
+            .add(cert.map_err(|e| invalid(&e))?)
+            .map_err(|e| invalid(&e))?;
+    }
+    Ok(Arc::new(roots))
+}
+
+/// Open a QUIC connection to the server, trusting the certificate in `cert`.
+#[cfg(feature = "quic")]
+pub async fn connect_quic(
+    addr: SocketAddr,
+    host: &str,
+    cert: &Path,
+) -> io::Result<quinn::Connection> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let config =
+        quinn::ClientConfig::with_root_certificates(roots(cert)?).map_err(|e| invalid(&e))?;
+
+    let local: SocketAddr = if addr.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let mut endpoint = quinn::Endpoint::client(local)?;
+    endpoint.set_default_client_config(config);
+    let connecting = endpoint.connect(addr, host).map_err(|e| invalid(&e))?;
+    Ok(connecting.await?)
+}
+
+/// Rooms to join, with the id of the last message received from each of them.
+pub type Rooms = HashMap<String, Option<u64>>;
+
+/// Start the thread talking to the server.
+pub fn spawn(
+    addr: ServerAddr,
+    name: String,
+    mut rooms: Rooms,
+    mut recv: Receiver<ClientFrame>,
+    send: Sender<Event>,
+    context: egui::Context,
+) {
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+
+    std::thread::spawn(move || {
+        rt.block_on(async move {
+            let mut backoff = Duration::from_secs(1);
+            loop {
+                let started = Instant::now();
+                let reason =
+                    match session(&addr, &name, &mut rooms, &mut recv, &send, &context).await {
+                        // The GUI has closed.
+                        Ok(()) => return,
+                        Err(e) => e.to_string(),
+                    };
+                tracing::warn!("disconnected from the server: {}", reason);
+                if send.send(Event::Disconnected(reason)).await.is_err() {
+                    return;
+                }
+                context.request_repaint();
+
+                // Only back off further if the connection didn't last.
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = Duration::from_secs(1);
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+    });
+}
+
+/// Connect to the server and pass frames both ways until the connection
+/// breaks. Returns `Ok` once the GUI is gone.
+async fn session(
+    addr: &ServerAddr,
+    name: &str,
+    rooms: &mut Rooms,
+    recv: &mut Receiver<ClientFrame>,
+    send: &Sender<Event>,
+    context: &egui::Context,
+) -> Result<(), SessionError> {
+    let (mut read, write) = tokio::time::timeout(HEARTBEAT_TIMEOUT, connect(addr)).await??;
+    let mut write = FramedWrite::new(write, LinesCodec::new());
+
+    let login = ClientFrame::Login {
+        name: name.to_string(),
+    };
+    write.send(protocol::encode(&login)).await?;
+    for (room, since) in rooms.iter() {
+        let frame = ClientFrame::Join {
+            room: room.clone(),
+            since: *since,
+        };
+        write.send(protocol::encode(&frame)).await?;
+    }
+    send.send(Event::Connected).await?;
+    context.request_repaint();
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut timeout = HEARTBEAT_TIMEOUT;
+    // When the server sent anything for the last time.
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            frame = recv.recv() => match frame {
+                Some(frame) => {
+                    if let ClientFrame::Join { room, since } = &frame {
+                        rooms.entry(room.clone()).or_insert(*since);
+                    }
+                    write.send(protocol::encode(&frame)).await?;
+                }
+                None => return Ok(()),
+            },
+            line = read.next() => {
+                let line = match line {
+                    Some(line) => line?,
+                    None => return Err("connection closed by the server".into()),
+                };
+                last_seen = Instant::now();
+                match protocol::decode(&line) {
+                    Ok(ServerFrame::Ping) => {
+                        write.send(protocol::encode(&ClientFrame::Pong)).await?;
+                    }
+                    Ok(ServerFrame::Pong) => {}
+                    Ok(ServerFrame::Welcome { heartbeat_interval, heartbeat_timeout }) => {
+                        let interval = Duration::from_secs(heartbeat_interval.max(1));
+                        heartbeat = tokio::time::interval_at(
+                            tokio::time::Instant::now() + interval,
+                            interval,
+                        );
+                        timeout = Duration::from_secs(heartbeat_timeout);
+                    }
+                    Ok(ServerFrame::Shutdown { reason }) => {
+                        tracing::info!("the server is going down: {}", reason);
+                        return Err("the server is restarting".into());
+                    }
+                    Ok(frame) => {
+                        if let ServerFrame::Message { id, room, .. } = &frame {
+                            // Replayed history can come after newer messages.
+                            let last = rooms.entry(room.clone()).or_default();
+                            *last = (*last).max(Some(*id));
+                        }
+                        send.send(Event::Frame(frame)).await?;
+                        context.request_repaint();
+                    }
+                    Err(e) => tracing::warn!("invalid frame from the server: {}", e),
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() >= timeout {
+                    return Err("the server stopped responding".into());
+                }
+                write.send(protocol::encode(&ClientFrame::Ping)).await?;
+            }
+        }
+    }
+}
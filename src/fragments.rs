@@ -0,0 +1,90 @@
+//! Named fragments of conversations.
+//!
+//! A fragment is a range of messages in a room, given by the ids of its first
+//! and last message, with its own title, note and tags. Messages are kept in
+//! the order of their ids, so new messages don't change what a fragment has.
+
+use serde::{Deserialize, Serialize};
+
+use crate::tags::{TagId, TagSet};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct FragmentId(u64);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Fragment {
+    pub id: FragmentId,
+    pub room: String,
+    /// Id of the first message.
+    pub first: u64,
+    /// Id of the last message.
+    pub last: u64,
+    pub title: String,
+    pub note: String,
+    pub tags: TagSet,
+}
+
+impl Fragment {
+    /// Whether a message is in the fragment. Messages without an id never are.
+    pub fn contains(&self, id: Option<u64>) -> bool {
+        id.is_some_and(|id| self.first <= id && id <= self.last)
+    }
+}
+
+/// All the user's fragments, in the order they were created.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Fragments {
+    fragments: Vec<Fragment>,
+    next_id: u64,
+}
+
+impl Fragments {
+    pub fn iter(&self) -> impl Iterator<Item = &Fragment> {
+        self.fragments.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Fragment> {
+        self.fragments.iter_mut()
+    }
+
+    /// Fragments of a room, in the order they were created.
+    pub fn in_room<'a>(&'a self, room: &'a str) -> impl Iterator<Item = &'a Fragment> {
+        self.fragments
+            .iter()
+            .filter(move |fragment| fragment.room == room)
+    }
+
+    pub fn add(
+        &mut self,
+        room: String,
+        first: u64,
+        last: u64,
+        title: String,
+        tags: TagSet,
+    ) -> FragmentId {
+        let id = FragmentId(self.next_id);
+        self.next_id += 1;
+        self.fragments.push(Fragment {
+            id,
+            room,
+            first,
+            last,
+            title,
+            note: String::new(),
+            tags,
+        });
+        id
+    }
+
+    pub fn remove(&mut self, id: FragmentId) {
+        self.fragments.retain(|fragment| fragment.id != id);
+    }
+
+    /// Take a deleted tag off every fragment.
+    pub fn remove_tag(&mut self, id: TagId) {
+        for fragment in &mut self.fragments {
+            fragment.tags.remove(&id);
+        }
+    }
+}
@@ -1,4 +1,17 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod connection;
+mod fragments;
+mod rules;
+mod suggest;
+mod tags;
+mod topics;
 pub use app::TagchatApp;
+#[cfg(feature = "quic")]
+pub use connection::connect_quic;
+
+pub mod protocol;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
@@ -0,0 +1,210 @@
+//! Frames exchanged between the client and the server.
+//!
+//! Every frame is a JSON object sent on its own line, with the `type` field
+//! telling which variant it is, e.g.
+//!
+//! ```text
+//! {"type":"login","name":"ala"}
+//! {"type":"message","room":"Wspólny","content":"hello"}
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Frames sent by a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Has to be the first frame sent on a connection.
+    Login {
+        name: String,
+    },
+    /// Start receiving messages sent to a room. Sending a message to a room
+    /// joins it too, but without `since`.
+    Join {
+        room: String,
+        /// Id of the last message from the room the client has. The server
+        /// first sends the recent messages which came after it, or all of
+        /// them if it's missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<u64>,
+    },
+    Message {
+        room: String,
+        content: String,
+    },
+    /// A private message to every connection of another user.
+    Direct {
+        to: String,
+        content: String,
+    },
+    /// Change the tags shared by the members of a room. Joins the room like
+    /// `Message` does.
+    EditTags {
+        room: String,
+        #[serde(flatten)]
+        edit: TagEdit,
+    },
+    /// Asks the server to answer with `Pong`, to check that it's still there.
+    Ping,
+    /// Answer to the server's `Ping`.
+    Pong,
+    /// An operator command. Can be sent instead of `Login`, every one of
+    /// them gets an `AdminReply`.
+    Admin {
+        /// Has to match `token` from the `[admin]` section of the server's
+        /// configuration.
+        token: String,
+        #[serde(flatten)]
+        command: AdminCommand,
+    },
+}
+
+/// A tag shared by the members of a room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SharedTag {
+    /// Unique within the room.
+    pub id: u64,
+    pub name: String,
+    /// Premultiplied linear RGBA, the way the client keeps colors.
+    pub color: [f32; 4],
+    /// User who created the tag, the only one who can change or delete it.
+    pub owner: String,
+}
+
+/// Ids of the messages which have a shared tag, by the id of the tag.
+pub type Tagged = BTreeMap<u64, BTreeSet<u64>>;
+
+/// Changes of a room's shared tags, e.g.
+///
+/// ```text
+/// {"type":"edit_tags","room":"Wspólny","edit":"create","name":"bug","color":[1,0,0,1]}
+/// {"type":"edit_tags","room":"Wspólny","edit":"assign","id":1,"messages":[7,8],"on":true}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "edit", rename_all = "snake_case")]
+pub enum TagEdit {
+    Create {
+        name: String,
+        color: [f32; 4],
+    },
+    /// Rename or recolor a tag. Only its owner can.
+    Change {
+        id: u64,
+        name: String,
+        color: [f32; 4],
+    },
+    /// Delete a tag, taking it off its messages. Only its owner can.
+    Delete {
+        id: u64,
+    },
+    /// Put a tag on messages, or with `on` set to `false` take it off them.
+    Assign {
+        id: u64,
+        messages: Vec<u64>,
+        on: bool,
+    },
+}
+
+/// Commands of the server's operators, e.g.
+///
+/// ```text
+/// {"type":"admin","token":"secret","command":"kick","user":"ala"}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, clap::Subcommand)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    /// List the connected users.
+    Peers,
+    /// List the rooms with the number of their members.
+    Rooms,
+    /// Disconnect every connection of a user.
+    Kick { user: String },
+    /// Disconnect a user and refuse their logins from now on.
+    Ban { user: String },
+    /// Let a banned user log in again.
+    Unban { user: String },
+    /// Send an announcement to everyone.
+    Announce { text: String },
+    /// Create an empty room.
+    CreateRoom { room: String },
+    /// Delete a room with its history. Its members are told about it.
+    DeleteRoom { room: String },
+    /// Read the configuration file again.
+    Reload,
+    /// Show the state of the server.
+    Stats,
+}
+
+/// Frames sent by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// A message sent to a room, including the ones sent by the client
+    /// itself.
+    Message {
+        /// Increases with every message sent to the room.
+        id: u64,
+        sender: String,
+        room: String,
+        content: String,
+        /// When the server got the message, in seconds since the Unix epoch.
+        /// Missing in messages kept by older servers.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent: Option<u64>,
+    },
+    /// A private message from another user.
+    Direct { sender: String, content: String },
+    /// Information from the server itself, e.g. that someone joined the chat
+    /// or that the client is sending messages too fast.
+    Notice { text: String },
+    /// All the shared tags of a room, sent after joining it. Replaces what
+    /// the client knew about them.
+    RoomTags {
+        room: String,
+        tags: Vec<SharedTag>,
+        tagged: Tagged,
+    },
+    /// A shared tag was created or changed.
+    TagChanged { room: String, tag: SharedTag },
+    /// A shared tag was deleted, and taken off its messages.
+    TagDeleted { room: String, id: u64 },
+    /// A shared tag was put on messages or taken off them.
+    Tagged {
+        room: String,
+        id: u64,
+        messages: Vec<u64>,
+        on: bool,
+    },
+    /// Sent right after logging in.
+    Welcome {
+        /// How often the client should ping the server, in seconds.
+        heartbeat_interval: u64,
+        /// How long the server waits for anything from the client before
+        /// disconnecting it, in seconds.
+        heartbeat_timeout: u64,
+    },
+    /// Message of the day, sent right after logging in.
+    Motd { text: String },
+    /// Server-wide announcement from an operator.
+    Announcement { text: String },
+    /// Sent to idle clients, which should answer with `Pong`.
+    Ping,
+    /// Answer to the client's `Ping`.
+    Pong,
+    /// The server is going down and will close the connection once the
+    /// queued messages are sent. Clients should reconnect later.
+    Shutdown { reason: String },
+    /// Result of an `Admin` frame.
+    AdminReply { ok: bool, text: String },
+}
+
+/// Serialize a frame into a single line, without the line terminator.
+pub fn encode<T: Serialize>(frame: &T) -> String {
+    serde_json::to_string(frame).expect("frames always serialize")
+}
+
+/// Parse a single line into a frame.
+pub fn decode<'a, T: Deserialize<'a>>(line: &'a str) -> Result<T, serde_json::Error> {
+    serde_json::from_str(line)
+}
@@ -0,0 +1,183 @@
+//! Rules which tag messages automatically, e.g. "content matches `#\d+` → tag
+//! `ticket`" or "sender is ci-bot → tag `ci`".
+//!
+//! Rules tag messages as they arrive, and can be run again over the messages
+//! which are already there.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use std::fmt;
+
+use crate::tags::{TagId, TagSet};
+
+/// What a rule looks at.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    /// The content matches a regular expression.
+    #[default]
+    Content,
+    /// The sender has the given name.
+    Sender,
+    /// The message was sent to the given room.
+    Room,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Field::Content => "content matches",
+            Field::Sender => "sender is",
+            Field::Room => "room is",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub field: Field,
+    /// A regular expression for `Field::Content`, a name otherwise.
+    pub pattern: String,
+    pub tag: TagId,
+    /// `pattern` compiled, `None` if it isn't a valid regular expression.
+    #[serde(skip)]
+    regex: OnceCell<Option<Regex>>,
+}
+
+impl Rule {
+    /// Create a rule, checking its regular expression.
+    pub fn new(field: Field, pattern: String, tag: TagId) -> Result<Rule, regex::Error> {
+        let regex = OnceCell::new();
+        if field == Field::Content {
+            let _ = regex.set(Some(Regex::new(&pattern)?));
+        }
+        Ok(Rule {
+            field,
+            pattern,
+            tag,
+            regex,
+        })
+    }
+
+    pub fn matches(&self, content: &str, sender: &str, room: &str) -> bool {
+        match self.field {
+            Field::Content => self
+                .regex
+                .get_or_init(|| Regex::new(&self.pattern).ok())
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(content)),
+            Field::Sender => sender == self.pattern,
+            Field::Room => room == self.pattern,
+        }
+    }
+}
+
+/// The user's rules, applied in the order they were added.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter()
+    }
+
+    pub fn add(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.rules.remove(index);
+    }
+
+    /// Delete the rules which give a deleted tag.
+    pub fn remove_tag(&mut self, id: TagId) {
+        self.rules.retain(|rule| rule.tag != id);
+    }
+
+    /// Tags the rules give a message.
+    pub fn tags(&self, content: &str, sender: &str, room: &str) -> TagSet {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(content, sender, room))
+            .map(|rule| rule.tag)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(id: u64) -> TagId {
+        serde_json::from_value(id.into()).unwrap()
+    }
+
+    #[test]
+    fn content_rule_matches_a_regex() {
+        let rule = Rule::new(Field::Content, r"#\d+".into(), tag(1)).unwrap();
+        assert!(rule.matches("fixes #42", "ala", "Wspólny"));
+        assert!(!rule.matches("fixes #x", "ala", "Wspólny"));
+        assert!(!rule.matches("ala", "#42", "#42"));
+    }
+
+    #[test]
+    fn sender_and_room_rules_match_exactly() {
+        let sender = Rule::new(Field::Sender, "ci-bot".into(), tag(1)).unwrap();
+        assert!(sender.matches("build passed", "ci-bot", "dev"));
+        assert!(!sender.matches("build passed", "ci-bot2", "dev"));
+        assert!(!sender.matches("build passed", "CI-bot", "dev"));
+        assert!(!sender.matches("ci-bot", "ala", "ci-bot"));
+
+        // Not a regular expression, so `.` is just a dot.
+        let room = Rule::new(Field::Room, "v1.2".into(), tag(2)).unwrap();
+        assert!(room.matches("hi", "ala", "v1.2"));
+        assert!(!room.matches("hi", "ala", "v1x2"));
+        assert!(!room.matches("hi", "ala", "v1.2-rc"));
+    }
+
+    #[test]
+    fn new_rejects_an_invalid_regex() {
+        assert!(Rule::new(Field::Content, "(unclosed".into(), tag(1)).is_err());
+        // Names aren't regular expressions.
+        assert!(Rule::new(Field::Sender, "(unclosed".into(), tag(1)).is_ok());
+    }
+
+    #[test]
+    fn regex_is_compiled_after_deserializing() {
+        let rule = Rule::new(Field::Content, r"^deploy\b".into(), tag(1)).unwrap();
+        let json = serde_json::to_string(&rule).unwrap();
+        let rule: Rule = serde_json::from_str(&json).unwrap();
+        assert!(rule.regex.get().is_none());
+        assert!(rule.matches("deploy done", "ala", "ops"));
+        assert!(rule.regex.get().is_some_and(Option::is_some));
+        assert!(!rule.matches("redeploy done", "ala", "ops"));
+
+        // A pattern which got invalid somehow never matches.
+        let json = json.replace(r"^deploy\\b", "(");
+        let rule: Rule = serde_json::from_str(&json).unwrap();
+        assert!(!rule.matches("(", "ala", "ops"));
+        assert!(rule.regex.get().is_some_and(Option::is_none));
+    }
+
+    #[test]
+    fn remove_tag_deletes_only_its_rules() {
+        let mut rules = Rules::default();
+        rules.add(Rule::new(Field::Sender, "ci-bot".into(), tag(1)).unwrap());
+        rules.add(Rule::new(Field::Content, "fail".into(), tag(2)).unwrap());
+        rules.add(Rule::new(Field::Room, "ops".into(), tag(1)).unwrap());
+        assert_eq!(
+            rules.tags("build failed", "ci-bot", "ops"),
+            TagSet::from([tag(1), tag(2)])
+        );
+
+        rules.remove_tag(tag(1));
+        assert_eq!(rules.iter().count(), 1);
+        assert_eq!(
+            rules.tags("build failed", "ci-bot", "ops"),
+            TagSet::from([tag(2)])
+        );
+    }
+}
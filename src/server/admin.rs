@@ -0,0 +1,173 @@
+//! Commands of the server's operators, sent with `tagchat-admin`.
+//!
+//! An admin connection starts with an `admin` frame instead of `login`. Every
+//! `admin` frame has to carry the token from the `[admin]` section of the
+//! configuration and gets an `admin_reply`. Without that section, or with a
+//! wrong token, the connection is closed after the first reply. A connection
+//! which doesn't send a command for `heartbeat.timeout` seconds is closed too.
+
+use std::error::Error;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio_util::sync::CancellationToken;
+
+use super::encode_line;
+use super::hub::Hub;
+use super::listener::{PeerAddr, Reader, Writer};
+use crate::protocol::{self, AdminCommand, ClientFrame, ServerFrame};
+
+/// Answer admin frames until the connection closes or the server shuts down.
+/// `token` and `command` come from the first frame.
+pub async fn serve(
+    hub: &Hub,
+    stop: &CancellationToken,
+    addr: PeerAddr,
+    mut token: String,
+    mut command: AdminCommand,
+    lines: &mut FramedRead<Reader, LinesCodec>,
+    mut writer: Writer,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let authorized = hub
+            .config()
+            .admin
+            .as_ref()
+            .is_some_and(|admin| same(&admin.token, &token));
+        if !authorized {
+            tracing::warn!(%addr, reason = "wrong token", "rejected an admin command");
+            hub.metrics.auth_failure();
+            let reply = ServerFrame::AdminReply {
+                ok: false,
+                text: "wrong token".into(),
+            };
+            writer.write_all(&encode_line(&reply)).await?;
+            return Ok(());
+        }
+
+        tracing::info!(%addr, ?command, "admin command");
+        let reply = match execute(hub, command).await {
+            Ok(text) => ServerFrame::AdminReply { ok: true, text },
+            Err(text) => ServerFrame::AdminReply { ok: false, text },
+        };
+        writer.write_all(&encode_line(&reply)).await?;
+
+        // Wait for the next command.
+        let idle = Duration::from_secs(hub.config().heartbeat.timeout);
+        loop {
+            let line = tokio::select! {
+                line = tokio::time::timeout(idle, lines.next()) => match line {
+                    Ok(Some(line)) => line?,
+                    Ok(None) => return Ok(()),
+                    Err(_) => {
+                        tracing::info!(%addr, "admin connection idle for too long");
+                        return Ok(());
+                    }
+                },
+                _ = stop.cancelled() => return Ok(()),
+            };
+            match protocol::decode(&line) {
+                Ok(ClientFrame::Admin {
+                    token: next_token,
+                    command: next_command,
+                }) => {
+                    token = next_token;
+                    command = next_command;
+                    break;
+                }
+                Ok(ClientFrame::Ping) => writer.write_all(&encode_line(&ServerFrame::Pong)).await?,
+                _ => tracing::warn!(%addr, %line, "unexpected frame from admin"),
+            }
+        }
+    }
+}
+
+/// Compare the tokens in time which doesn't depend on where they differ.
+fn same(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn execute(hub: &Hub, command: AdminCommand) -> Result<String, String> {
+    match command {
+        AdminCommand::Peers => {
+            let mut out = String::new();
+            for (addr, username) in hub.peers() {
+                let _ = writeln!(out, "{} {}", username, addr);
+            }
+            Ok(out)
+        }
+        AdminCommand::Rooms => {
+            let mut out = String::new();
+            for (name, room) in hub.rooms() {
+                if let Some(info) = room.info().await {
+                    let _ = writeln!(
+                        out,
+                        "{}: {} members, {} recent messages",
+                        name,
+                        info.members.len(),
+                        info.messages
+                    );
+                }
+            }
+            Ok(out)
+        }
+        AdminCommand::Kick { user } => match hub.kick(&user) {
+            0 => Err(format!("{} is not connected", user)),
+            n => Ok(format!("kicked {} ({} connections)", user, n)),
+        },
+        AdminCommand::Ban { user } => {
+            let changed = hub.set_banned(&user, true).map_err(|e| e.to_string())?;
+            let kicked = hub.kick(&user);
+            if changed {
+                Ok(format!("banned {}, kicked {} connections", user, kicked))
+            } else {
+                Err(format!("{} is already banned", user))
+            }
+        }
+        AdminCommand::Unban { user } => {
+            if hub.set_banned(&user, false).map_err(|e| e.to_string())? {
+                Ok(format!("unbanned {}", user))
+            } else {
+                Err(format!("{} is not banned", user))
+            }
+        }
+        AdminCommand::Announce { text } => {
+            hub.broadcast(None, encode_line(&ServerFrame::Announcement { text }));
+            Ok(format!("sent to {} users", hub.stats().peers))
+        }
+        AdminCommand::CreateRoom { room } => {
+            if hub.rooms().iter().any(|(name, _)| *name == room) {
+                return Err(format!("{} already exists", room));
+            }
+            hub.room(&room);
+            Ok(format!("created {}", room))
+        }
+        AdminCommand::DeleteRoom { room } => match hub.delete_room(&room).await {
+            Ok(true) => Ok(format!("deleted {}", room)),
+            Ok(false) => Err(format!("there is no room {}", room)),
+            Err(e) => Err(format!("deleted {}, but couldn't save it: {}", room, e)),
+        },
+        AdminCommand::Reload => match hub.reload() {
+            Ok(restart) if restart.is_empty() => Ok("configuration reloaded".into()),
+            Ok(restart) => Ok(format!(
+                "configuration reloaded, restart the server to change: {}",
+                restart.join(", ")
+            )),
+            Err(e) => Err(format!("invalid configuration: {}", e)),
+        },
+        AdminCommand::Stats => {
+            let stats = hub.stats();
+            Ok(format!(
+                "peers: {}\nrooms: {}\nqueued messages: {}\nlongest queue: {}\ndropped messages: {}\n",
+                stats.peers, stats.rooms, stats.queued, stats.longest_queue, stats.dropped,
+            ))
+        }
+    }
+}
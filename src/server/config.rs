@@ -0,0 +1,523 @@
+//! Server configuration.
+//!
+//! The configuration is read from a TOML file and can be partially overridden
+//! from the command line. A file containing every option with its default
+//! value looks like this:
+//!
+//! ```toml
+//! bind = ["127.0.0.1"]
+//! port = 6142
+//! data_dir = "data"
+//! log_level = "info"
+//! log_format = "text"
+//! motd = "Welcome to TagChat!"
+//! stats_interval = 60
+//! shutdown_timeout = 5
+//!
+//! [limits]
+//! max_connections = 1024
+//! max_connections_per_ip = 16
+//! max_line_length = 4096
+//! warn_after = 3
+//! disconnect_after = 10
+//! connection_rate = { burst = 10, per_second = 5.0 }
+//! account_rate = { burst = 20, per_second = 8.0 }
+//! outbound_queue = 256
+//! slow_consumer = "resync"
+//! history = 100
+//!
+//! [heartbeat]
+//! interval = 15
+//! timeout = 45
+//! text_timeout = 900
+//!
+//! [tls]
+//! cert = "cert.pem"
+//! key = "key.pem"
+//!
+//! [metrics]
+//! listen = "127.0.0.1:9142"
+//!
+//! [admin]
+//! token = "change me"
+//!
+//! [log_file]
+//! directory = "logs"
+//! prefix = "server.log"
+//! rotation = "daily"
+//!
+//! [unix_socket]
+//! path = "tagchat.sock"
+//! mode = 0o660
+//!
+//! [quic]
+//! listen = "127.0.0.1:6143"
+//!
+//! [irc]
+//! listen = "127.0.0.1:6667"
+//! ```
+//!
+//! Entries of `bind` are IP addresses optionally followed by a port, e.g.
+//! `"0.0.0.0"`, `"::"` or `"[::1]:7000"`. Addresses without a port use `port`.
+//!
+//! The server reads the file again on SIGHUP and on the `reload` admin
+//! command. Changes of `bind`, `port`, `unix_socket`, `quic`, `irc`,
+//! `data_dir`, `stats_interval`, `tls`, `metrics`, `log_format` and
+//! `log_file` wait for a restart. Most limits and the heartbeat apply to
+//! connections opened after the reload, the rate limits to everyone right
+//! away.
+
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tracing_subscriber::filter::LevelFilter;
+
+use super::outbox::SlowConsumerPolicy;
+
+/// Port used when neither the config file nor the command line sets one.
+pub const DEFAULT_PORT: u16 = 6142;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to listen on, each one optionally with its own port.
+    pub bind: Vec<String>,
+    /// Port used by `bind` entries which don't specify one.
+    pub port: u16,
+    /// Unix domain socket to listen on besides TCP. Only on Unix.
+    pub unix_socket: Option<UnixSocket>,
+    /// QUIC listener, off when missing. Needs the `quic` feature.
+    pub quic: Option<Quic>,
+    /// IRC gateway, off when missing.
+    pub irc: Option<Irc>,
+    /// Directory where the server keeps its persistent state.
+    pub data_dir: PathBuf,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    /// Where to write the logs instead of stdout.
+    pub log_file: Option<LogFile>,
+    /// Message of the day, sent to every client after logging in.
+    pub motd: Option<String>,
+    /// How often to log statistics, in seconds. 0 turns them off.
+    pub stats_interval: u64,
+    /// How long to wait, in seconds, for the clients to receive the messages
+    /// still queued for them when the server shuts down.
+    pub shutdown_timeout: u64,
+    pub limits: Limits,
+    pub heartbeat: Heartbeat,
+    /// Serve the `bind` addresses over TLS, off when missing.
+    pub tls: Option<Tls>,
+    /// Where to serve the metrics, off when missing.
+    pub metrics: Option<Metrics>,
+    /// Accepting operator commands, off when missing.
+    pub admin: Option<Admin>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum number of clients connected at the same time.
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// Maximum length of a single line sent by a client, in bytes.
+    pub max_line_length: usize,
+    /// Number of throttled messages after which messages get dropped with a
+    /// warning.
+    pub warn_after: u32,
+    /// Number of throttled or dropped messages after which the client gets
+    /// disconnected.
+    pub disconnect_after: u32,
+    /// Message rate of a single connection.
+    pub connection_rate: RateLimit,
+    /// Message rate of all connections of a single user together.
+    pub account_rate: RateLimit,
+    /// Maximum number of messages waiting to be sent to a single client.
+    pub outbound_queue: usize,
+    /// What to do with a client whose outbound queue is full.
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Number of recent messages each room keeps for clients which reconnect.
+    pub history: usize,
+}
+
+/// Token bucket parameters, see `limits::TokenBucket`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Number of messages which can be sent at once.
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// Detecting dead connections. Both values are in seconds.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    /// A client which hasn't sent anything for this long gets pinged.
+    pub interval: u64,
+    /// A client which hasn't sent anything for this long gets disconnected.
+    pub timeout: u64,
+    /// The same for plain text clients, which don't get pinged.
+    pub text_timeout: u64,
+}
+
+/// Certificate of the server, see `tls`. QUIC uses it too.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// HTTP endpoint with metrics in the Prometheus text format.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Metrics {
+    /// Address of the endpoint, served at `/metrics`. Better kept local.
+    pub listen: SocketAddr,
+}
+
+/// Local clients, see `listener`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocket {
+    pub path: PathBuf,
+    /// Permissions of the socket file. Only users allowed to write to it can
+    /// connect.
+    #[serde(default = "UnixSocket::default_mode")]
+    pub mode: u32,
+}
+
+impl UnixSocket {
+    pub fn default_mode() -> u32 {
+        0o660
+    }
+}
+
+/// QUIC transport, see `quic`. Uses the certificate from `[tls]`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Quic {
+    /// UDP address to listen on.
+    pub listen: SocketAddr,
+}
+
+/// Listener for IRC clients, see `irc`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Irc {
+    /// TCP address to listen on.
+    pub listen: SocketAddr,
+}
+
+/// How log lines are formatted.
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+/// Log file rotated by time.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LogFile {
+    pub directory: PathBuf,
+    /// Name of the files, followed by the date unless `rotation` is `never`.
+    #[serde(default = "LogFile::default_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub rotation: Rotation,
+}
+
+/// How often a new log file is started.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl LogFile {
+    fn default_prefix() -> String {
+        "server.log".into()
+    }
+}
+
+/// Operator commands, see `protocol::AdminCommand`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Admin {
+    /// Secret sent with every command.
+    pub token: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![Ipv4Addr::LOCALHOST.to_string()],
+            port: DEFAULT_PORT,
+            unix_socket: None,
+            quic: None,
+            irc: None,
+            data_dir: "data".into(),
+            log_level: "info".into(),
+            log_format: LogFormat::Text,
+            log_file: None,
+            motd: None,
+            stats_interval: 60,
+            shutdown_timeout: 5,
+            limits: Default::default(),
+            heartbeat: Default::default(),
+            tls: None,
+            metrics: None,
+            admin: None,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: 15,
+            timeout: 45,
+            text_timeout: 900,
+        }
+    }
+}
+
+impl Limits {
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, value) in [
+            ("max_connections", self.max_connections),
+            ("max_connections_per_ip", self.max_connections_per_ip),
+            ("max_line_length", self.max_line_length),
+            ("outbound_queue", self.outbound_queue),
+        ] {
+            if value == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "limits.{}: must be greater than 0",
+                    name
+                )));
+            }
+        }
+        if self.warn_after > self.disconnect_after {
+            return Err(ConfigError::Invalid(
+                "limits.warn_after: must not be greater than disconnect_after".into(),
+            ));
+        }
+        for (name, rate) in [
+            ("connection_rate", &self.connection_rate),
+            ("account_rate", &self.account_rate),
+        ] {
+            if rate.burst == 0 || rate.per_second.is_nan() || rate.per_second <= 0. {
+                return Err(ConfigError::Invalid(format!(
+                    "limits.{}: burst and per_second must be greater than 0",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 1024,
+            max_connections_per_ip: 16,
+            max_line_length: 4096,
+            warn_after: 3,
+            disconnect_after: 10,
+            connection_rate: RateLimit {
+                burst: 10,
+                per_second: 5.,
+            },
+            account_rate: RateLimit {
+                burst: 20,
+                per_second: 8.,
+            },
+            outbound_queue: 256,
+            slow_consumer: SlowConsumerPolicy::Resync,
+            history: 100,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Read the configuration from a TOML file. Options missing from the file
+    /// keep their default values.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    /// Undo the changes of settings which can't change while the server is
+    /// `running` with them. Returns the names of the settings.
+    pub fn keep_restart_only(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut kept = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {$(
+                if self.$field != running.$field {
+                    self.$field = running.$field.clone();
+                    kept.push(stringify!($field));
+                }
+            )*};
+        }
+        keep!(
+            bind,
+            port,
+            unix_socket,
+            quic,
+            irc,
+            data_dir,
+            stats_interval,
+            tls,
+            metrics,
+            log_format,
+            log_file
+        );
+        kept
+    }
+
+    /// All socket addresses the server should listen on.
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        let mut addrs = Vec::new();
+        for entry in &self.bind {
+            let addr = if let Ok(addr) = entry.parse::<SocketAddr>() {
+                addr
+            } else if let Ok(ip) = entry
+                .trim_matches(|c| c == '[' || c == ']')
+                .parse::<IpAddr>()
+            {
+                SocketAddr::new(ip, self.port)
+            } else {
+                return Err(ConfigError::Invalid(format!(
+                    "bind: `{}` is not an IP address",
+                    entry
+                )));
+            };
+            if addrs.contains(&addr) {
+                return Err(ConfigError::Invalid(format!(
+                    "bind: {} is listed more than once",
+                    addr
+                )));
+            }
+            addrs.push(addr);
+        }
+        Ok(addrs)
+    }
+
+    /// Check that the configuration is usable. Only looks at the files it
+    /// names and changes nothing, so that reloading can check a
+    /// configuration without applying it.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bind.is_empty() {
+            return Err(ConfigError::Invalid(
+                "bind: at least one address is required".into(),
+            ));
+        }
+        if self.listen_addrs()?.iter().any(|addr| addr.port() == 0) {
+            return Err(ConfigError::Invalid("port: 0 is not a valid port".into()));
+        }
+
+        if let Some(unix) = &self.unix_socket {
+            if cfg!(not(unix)) {
+                return Err(ConfigError::Invalid(
+                    "unix_socket: not supported on this system".into(),
+                ));
+            }
+            if unix.mode > 0o777 {
+                return Err(ConfigError::Invalid(format!(
+                    "unix_socket.mode: {:o} is not a valid file mode",
+                    unix.mode
+                )));
+            }
+        }
+
+        if self.quic.is_some() && cfg!(not(feature = "quic")) {
+            return Err(ConfigError::Invalid(
+                "quic: the server was built without the `quic` feature".into(),
+            ));
+        }
+
+        if self.log_level.parse::<LevelFilter>().is_err() {
+            return Err(ConfigError::Invalid(format!(
+                "log_level: `{}` is not one of off, error, warn, info, debug, trace",
+                self.log_level
+            )));
+        }
+
+        if let Some(log_file) = &self.log_file {
+            if log_file.prefix.is_empty() {
+                return Err(ConfigError::Invalid(
+                    "log_file.prefix: must not be empty".into(),
+                ));
+            }
+        }
+
+        self.limits.validate()?;
+
+        if self.heartbeat.interval == 0 || self.heartbeat.timeout <= self.heartbeat.interval {
+            return Err(ConfigError::Invalid(
+                "heartbeat: interval must be greater than 0 and less than timeout".into(),
+            ));
+        }
+        if self.heartbeat.text_timeout < self.heartbeat.interval {
+            return Err(ConfigError::Invalid(
+                "heartbeat.text_timeout: must not be less than interval".into(),
+            ));
+        }
+
+        if let Some(tls) = &self.tls {
+            for path in [&tls.cert, &tls.key] {
+                if !path.is_file() {
+                    return Err(ConfigError::Invalid(format!(
+                        "tls: {} is not a file",
+                        path.display()
+                    )));
+                }
+            }
+        }
+
+        if matches!(&self.admin, Some(admin) if admin.token.is_empty()) {
+            return Err(ConfigError::Invalid(
+                "admin.token: must not be empty".into(),
+            ));
+        }
+
+        if self.data_dir.exists() && !self.data_dir.is_dir() {
+            return Err(ConfigError::Invalid(format!(
+                "data_dir: {} is not a directory",
+                self.data_dir.display()
+            )));
+        }
+        Ok(())
+    }
+}
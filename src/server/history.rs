@@ -0,0 +1,38 @@
+//! Recent messages of every room, kept in `data_dir` between restarts.
+//!
+//! The rooms keep their history in memory. It is read from disk once at
+//! startup and written back when the server shuts down, and whenever shared
+//! tags change.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use super::shared_tags::RoomTags;
+use super::storage;
+use crate::protocol::ServerFrame;
+
+const FILE_NAME: &str = "history.json";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RoomHistory {
+    /// Id the next message sent to the room will get.
+    pub next_id: u64,
+    /// `ServerFrame::Message`s, oldest first.
+    pub messages: Vec<ServerFrame>,
+    /// Missing in history saved before rooms had shared tags.
+    #[serde(default)]
+    pub tags: RoomTags,
+}
+
+/// History of every room, by room name.
+pub type Snapshot = HashMap<String, RoomHistory>;
+
+pub fn load(data_dir: &Path) -> io::Result<Snapshot> {
+    storage::load(data_dir, FILE_NAME)
+}
+
+pub fn save(data_dir: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    storage::save(data_dir, FILE_NAME, snapshot)
+}
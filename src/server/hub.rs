@@ -0,0 +1,375 @@
+//! State shared by all connections.
+//!
+//! Everything here is only touched when clients connect, disconnect or join
+//! rooms, and by the operators' commands. Messages go straight to the rooms'
+//! tasks, see `room`.
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use super::config::{Config, ConfigError, Limits};
+use super::history::{self, RoomHistory, Snapshot};
+use super::limits::TokenBucket;
+use super::listener::PeerAddr;
+use super::metrics::Metrics;
+use super::outbox::Outbox;
+use super::room::Room;
+use super::storage;
+
+/// Where the banned users are saved in `data_dir`.
+const BANS_FILE: &str = "bans.json";
+
+/// Where the ids deleted rooms had got to are saved in `data_dir`.
+const DELETED_FILE: &str = "deleted_rooms.json";
+
+/// Reads the configuration again, see `Hub::reload`.
+pub type Reload = Box<dyn Fn() -> Result<Config, ConfigError> + Send + Sync>;
+
+struct PeerEntry {
+    username: String,
+    outbox: Arc<Outbox>,
+    /// Cancelled to disconnect the peer.
+    kick: CancellationToken,
+}
+
+#[derive(Default)]
+struct Connections {
+    /// Number of open connections, including the ones which haven't logged in
+    /// yet.
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Snapshot of the state of the server.
+pub struct Stats {
+    pub peers: usize,
+    pub rooms: usize,
+    pub queued: usize,
+    pub longest_queue: usize,
+    pub dropped: u64,
+}
+
+pub struct Hub {
+    /// The configuration. Connections take the current one when they start.
+    config: RwLock<Arc<Config>>,
+    reload: Reload,
+    peers: RwLock<HashMap<PeerAddr, PeerEntry>>,
+    rooms: RwLock<HashMap<String, Room>>,
+    /// Users who can't log in.
+    banned: RwLock<HashSet<String>>,
+    /// Id the next message of every deleted room would have got. A room
+    /// created again with the same name goes on from there, so that clients
+    /// which still have the old messages put the new ones after them.
+    deleted: Mutex<HashMap<String, u64>>,
+    /// Notified when the shared tags of a room change, see `tags_edited`.
+    tags_edited: Notify,
+    connections: Mutex<Connections>,
+    /// Rate limits of users, shared by all their connections.
+    accounts: Mutex<HashMap<String, Arc<Mutex<TokenBucket>>>>,
+    /// Messages dropped from the queues of peers which already disconnected.
+    dropped: AtomicU64,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Hub {
+    /// Create the hub, starting the rooms saved in `data_dir`.
+    pub fn new(config: Config, reload: Reload) -> io::Result<Hub> {
+        let metrics = Arc::new(Metrics::default());
+        let rooms = history::load(&config.data_dir)?
+            .into_iter()
+            .map(|(name, messages)| {
+                let room = Room::spawn(
+                    name.clone(),
+                    messages,
+                    config.limits.history,
+                    metrics.clone(),
+                );
+                (name, room)
+            })
+            .collect();
+        Ok(Hub {
+            banned: RwLock::new(storage::load(&config.data_dir, BANS_FILE)?),
+            deleted: Mutex::new(storage::load(&config.data_dir, DELETED_FILE)?),
+            tags_edited: Notify::new(),
+            config: RwLock::new(Arc::new(config)),
+            reload,
+            peers: Default::default(),
+            rooms: RwLock::new(rooms),
+            connections: Default::default(),
+            accounts: Default::default(),
+            dropped: Default::default(),
+            metrics,
+        })
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Read the configuration and the banned users again. Connections opened
+    /// from now on use the new configuration, banned users get disconnected.
+    /// Returns the settings which need a restart to change, see
+    /// `Config::keep_restart_only`.
+    pub fn reload(&self) -> Result<Vec<&'static str>, ConfigError> {
+        let mut config = (self.reload)()?;
+        let running = self.config();
+        let restart = config.keep_restart_only(&running);
+        let bans: HashSet<String> = storage::load(&running.data_dir, BANS_FILE)
+            .map_err(|e| ConfigError::Io(running.data_dir.join(BANS_FILE), e))?;
+
+        *self.config.write().unwrap() = Arc::new(config);
+        for peer in self.peers.read().unwrap().values() {
+            if bans.contains(&peer.username) {
+                peer.kick.cancel();
+            }
+        }
+        *self.banned.write().unwrap() = bans;
+
+        if restart.is_empty() {
+            tracing::info!("configuration reloaded");
+        } else {
+            tracing::warn!(
+                restart_required = ?restart,
+                "configuration reloaded, some changes need a restart"
+            );
+        }
+        Ok(restart)
+    }
+
+    /// Register a new connection, unless it exceeds the connection limits.
+    /// Local connections, without an IP address, only count towards the
+    /// total.
+    pub fn connect(&self, ip: Option<IpAddr>, limits: &Limits) -> Result<(), &'static str> {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.total >= limits.max_connections {
+            return Err("too many clients");
+        }
+        if let Some(ip) = ip {
+            let count = connections.per_ip.entry(ip).or_default();
+            if *count >= limits.max_connections_per_ip {
+                return Err("too many clients from this address");
+            }
+            *count += 1;
+        }
+        connections.total += 1;
+        Ok(())
+    }
+
+    /// Unregister a connection registered with `connect`.
+    pub fn disconnect(&self, ip: Option<IpAddr>) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Some(ip) = ip {
+            if let Some(count) = connections.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.per_ip.remove(&ip);
+                }
+            }
+        }
+        drop(connections);
+
+        // A full bucket nobody else uses is no different from a new one.
+        self.accounts
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.lock().unwrap().is_full());
+    }
+
+    /// Rate limit of the given user.
+    pub fn account(&self, username: &str, limits: &Limits) -> Arc<Mutex<TokenBucket>> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(&limits.account_rate))))
+            .clone()
+    }
+
+    pub fn add_peer(
+        &self,
+        addr: PeerAddr,
+        username: String,
+        outbox: Arc<Outbox>,
+        kick: CancellationToken,
+    ) {
+        let peer = PeerEntry {
+            username,
+            outbox,
+            kick,
+        };
+        self.peers.write().unwrap().insert(addr, peer);
+    }
+
+    pub fn remove_peer(&self, addr: &PeerAddr) {
+        if let Some(peer) = self.peers.write().unwrap().remove(addr) {
+            self.dropped
+                .fetch_add(peer.outbox.dropped(), Ordering::Relaxed);
+            tracing::debug!(%addr, user = %peer.username, "removed peer");
+        }
+    }
+
+    /// Logged in users with the addresses they connected from.
+    pub fn peers(&self) -> Vec<(PeerAddr, String)> {
+        let mut peers: Vec<_> = self
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.username.clone()))
+            .collect();
+        peers.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        peers
+    }
+
+    /// Disconnect every connection of a user. Returns the number of
+    /// connections.
+    pub fn kick(&self, username: &str) -> usize {
+        let peers = self.peers.read().unwrap();
+        let mut kicked = 0;
+        for peer in peers.values().filter(|peer| peer.username == username) {
+            peer.kick.cancel();
+            kicked += 1;
+        }
+        kicked
+    }
+
+    pub fn is_banned(&self, username: &str) -> bool {
+        self.banned.read().unwrap().contains(username)
+    }
+
+    /// Ban or unban a user. Returns whether anything changed.
+    pub fn set_banned(&self, username: &str, banned: bool) -> io::Result<bool> {
+        let mut bans = self.banned.write().unwrap();
+        let changed = if banned {
+            bans.insert(username.to_string())
+        } else {
+            bans.remove(username)
+        };
+        if changed {
+            storage::save(&self.config().data_dir, BANS_FILE, &*bans)?;
+        }
+        Ok(changed)
+    }
+
+    /// Note that the shared tags of a room changed, so that they get saved.
+    pub fn tags_edited(&self) {
+        self.tags_edited.notify_one();
+    }
+
+    /// Wait until shared tags change. Changes made since the last call count
+    /// too, all of them together.
+    pub async fn edited_tags(&self) {
+        self.tags_edited.notified().await
+    }
+
+    /// Queue an encoded message for every peer, except for the sender if
+    /// there is one.
+    pub fn broadcast(&self, sender: Option<PeerAddr>, message: Bytes) {
+        for (addr, peer) in self.peers.read().unwrap().iter() {
+            if Some(*addr) != sender {
+                peer.outbox.push(message.clone());
+            }
+        }
+    }
+
+    /// Queue an encoded message for every connection of a user. Returns the
+    /// number of connections.
+    pub fn direct(&self, username: &str, message: Bytes) -> usize {
+        let peers = self.peers.read().unwrap();
+        let mut sent = 0;
+        for peer in peers.values().filter(|peer| peer.username == username) {
+            peer.outbox.push(message.clone());
+            sent += 1;
+        }
+        sent
+    }
+
+    /// Find a room, creating it if it doesn't exist yet.
+    pub fn room(&self, name: &str) -> Room {
+        if let Some(room) = self.rooms.read().unwrap().get(name) {
+            return room.clone();
+        }
+        self.rooms
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let history = RoomHistory {
+                    next_id: self.deleted.lock().unwrap().get(name).copied().unwrap_or(1),
+                    ..Default::default()
+                };
+                Room::spawn(
+                    name.to_string(),
+                    history,
+                    self.config().limits.history,
+                    self.metrics.clone(),
+                )
+            })
+            .clone()
+    }
+
+    /// All rooms, sorted by name.
+    pub fn rooms(&self) -> Vec<(String, Room)> {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, room)| (name.clone(), room.clone()))
+            .collect();
+        rooms.sort_by(|a, b| a.0.cmp(&b.0));
+        rooms
+    }
+
+    /// Close a room and forget it, remembering where its ids got to. Returns
+    /// `false` if there is no such room.
+    pub async fn delete_room(&self, name: &str) -> io::Result<bool> {
+        let room = match self.rooms.read().unwrap().get(name) {
+            Some(room) => room.clone(),
+            None => return Ok(false),
+        };
+        // The room stops first, so that no message gets an id after the one
+        // remembered. Until it's forgotten, messages sent to it are dropped.
+        let Some(next_id) = room.close().await else {
+            return Ok(false);
+        };
+        let mut rooms = self.rooms.write().unwrap();
+        rooms.remove(name);
+        let mut deleted = self.deleted.lock().unwrap();
+        deleted.insert(name.to_string(), next_id);
+        storage::save(&self.config().data_dir, DELETED_FILE, &*deleted)?;
+        Ok(true)
+    }
+
+    /// Recent messages of every room.
+    pub async fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        for (name, room) in self.rooms() {
+            snapshot.insert(name, room.snapshot().await);
+        }
+        snapshot
+    }
+
+    pub fn stats(&self) -> Stats {
+        let peers = self.peers.read().unwrap();
+        let depths = peers.values().map(|peer| peer.outbox.depth());
+        Stats {
+            peers: peers.len(),
+            rooms: self.rooms.read().unwrap().len(),
+            queued: depths.clone().sum(),
+            longest_queue: depths.max().unwrap_or(0),
+            dropped: self.dropped.load(Ordering::Relaxed)
+                + peers
+                    .values()
+                    .map(|peer| peer.outbox.dropped())
+                    .sum::<u64>(),
+        }
+    }
+}
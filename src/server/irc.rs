@@ -0,0 +1,1046 @@
+//! IRC gateway, so that IRC clients can chat with everyone else.
+//!
+//! Clients connecting to the `[irc]` address speak IRC instead of the JSON
+//! protocol. `NICK` and `USER` log in, channels are rooms (`#Wspólny` is the
+//! room `Wspólny`), `JOIN` and `PART` join and leave them and `PRIVMSG` sends
+//! a message to a room, or a private message to every connection of a user.
+//! `NAMES`, `WHO` and `LIST` show who is in which room.
+//!
+//! Clients which ask for the IRCv3 `message-tags` capability get the id of
+//! every message as its `msgid` tag and the names of the shared tags it has
+//! as `+tagchat/tags`, e.g. `@msgid=Wspólny/7;+tagchat/tags=bug,ui`. When the
+//! shared tags of a message change, they get a `TAGMSG` with the message's id
+//! in `+draft/reply` and its new tags in `+tagchat/tags`, and they can send
+//! one the same way to change them. Tags the room doesn't have yet are
+//! created. Other tags sent by clients are ignored. With `echo-message` they
+//! also get their own messages back, as the JSON clients do.
+//!
+//! Nicks are the names of users, so a nick someone is connected with can't
+//! be used by another client. Rooms whose names IRC can't write as a channel,
+//! with a space or a comma, aren't listed and can't be joined from IRC.
+//! Line breaks and NUL in anything from the other clients, e.g. in messages,
+//! are replaced with spaces, so that they can't start a line of their own.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::Write;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+use super::hub::Hub;
+use super::listener::{Reader, Writer};
+use super::Input;
+use crate::protocol::{self, ClientFrame, ServerFrame, SharedTag, TagEdit, Tagged};
+
+/// Name of the server in replies.
+const SERVER: &str = "tagchat";
+
+/// Capabilities the server supports.
+const CAPABILITIES: [&str; 2] = ["message-tags", "echo-message"];
+
+/// Color of the shared tags created by IRC clients, which can't choose one.
+const NEW_TAG_COLOR: [f32; 4] = [0.5, 0.5, 0.5, 1.];
+
+/// An IRC client which has registered.
+pub struct Session {
+    pub nick: String,
+    /// Send message tags.
+    tags: bool,
+    /// Send the client's own messages back.
+    echo: bool,
+    /// Shared tags of the rooms the client is in, kept up to date from the
+    /// frames it gets.
+    rooms: HashMap<String, Shared>,
+}
+
+/// Shared tags of a room.
+struct Shared {
+    /// Names of the tags, by their ids.
+    names: BTreeMap<u64, String>,
+    tagged: Tagged,
+}
+
+impl Shared {
+    fn new(tags: &[SharedTag], tagged: &Tagged) -> Shared {
+        Shared {
+            names: tags.iter().map(|tag| (tag.id, tag.name.clone())).collect(),
+            tagged: tagged.clone(),
+        }
+    }
+
+    fn has(&self, tag: u64, message: u64) -> bool {
+        self.tagged
+            .get(&tag)
+            .is_some_and(|messages| messages.contains(&message))
+    }
+
+    /// Names of the tags on a message, as `+tagchat/tags` lists them.
+    fn of(&self, message: u64) -> String {
+        let names: Vec<_> = self
+            .names
+            .iter()
+            .filter(|(id, _)| self.has(**id, message))
+            .map(|(_, name)| name.as_str())
+            .collect();
+        names.join(",")
+    }
+}
+
+impl Session {
+    /// A numeric reply to this client.
+    fn numeric(&self, code: &str, text: &str) -> String {
+        format!(":{} {} {} {}\r\n", SERVER, code, self.nick, clean(text))
+    }
+}
+
+/// A line from a client, with its prefix dropped.
+struct Command<'a> {
+    /// Message tags, with their values unescaped.
+    tags: Vec<(&'a str, String)>,
+    name: String,
+    params: Vec<&'a str>,
+}
+
+impl Command<'_> {
+    fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn split(line: &str) -> Option<Command<'_>> {
+    let mut line = line.trim_end();
+    let mut tags = Vec::new();
+    if let Some(rest) = line.strip_prefix('@') {
+        let (list, rest) = rest.split_once(' ')?;
+        tags = list
+            .split(';')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| {
+                let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+                (key, unescape(value))
+            })
+            .collect();
+        line = rest.trim_start();
+    }
+    if line.starts_with(':') {
+        line = line.split_once(' ')?.1.trim_start();
+    }
+    let (line, trailing) = match line.split_once(" :") {
+        Some((line, trailing)) => (line, Some(trailing)),
+        None => (line, None),
+    };
+    let mut words = line.split(' ').filter(|word| !word.is_empty());
+    let name = words.next()?.to_ascii_uppercase();
+    let params = words.chain(trailing).collect();
+    Some(Command { tags, name, params })
+}
+
+/// `text` without the characters which end an IRC line, or a C string.
+fn clean(text: &str) -> Cow<'_, str> {
+    if text.contains(['\r', '\n', '\0']) {
+        Cow::Owned(text.replace(['\r', '\n', '\0'], " "))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Whether a room can be written as an IRC channel, `#` and its name.
+fn is_channel(room: &str) -> bool {
+    !room.is_empty() && !room.contains(|c: char| c.is_control() || c == ' ' || c == ',')
+}
+
+/// The nick of a user. Spaces, allowed in TagChat names, are replaced.
+fn nick(username: &str) -> String {
+    clean(username).replace(' ', "_")
+}
+
+/// `nick!user@host` of a user, as messages from them start with.
+fn prefix(username: &str) -> String {
+    let nick = nick(username);
+    format!("{}!{}@{}", nick, nick, SERVER)
+}
+
+/// Escape the value of a message tag.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            '\0' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Undo `escape`.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+/// The `msgid` tag of a message, escaped.
+fn msgid(room: &str, id: u64) -> String {
+    escape(&format!("{}/{}", room, id))
+}
+
+/// A `TAGMSG` telling a client which shared tags a message has now.
+fn tagmsg(room: &str, message: u64, shared: &Shared) -> String {
+    format!(
+        "@+draft/reply={};+tagchat/tags={} :{} TAGMSG #{}\r\n",
+        msgid(room, message),
+        escape(&shared.of(message)),
+        SERVER,
+        clean(room)
+    )
+}
+
+/// Answer a `CAP` command.
+fn capabilities(nick: &str, tags: &mut bool, echo: &mut bool, params: &[&str]) -> Option<String> {
+    let nick = if nick.is_empty() { "*" } else { nick };
+    match params.first().map(|s| s.to_ascii_uppercase()).as_deref() {
+        Some("LS") => Some(format!(
+            ":{} CAP {} LS :{}\r\n",
+            SERVER,
+            nick,
+            CAPABILITIES.join(" ")
+        )),
+        Some("LIST") => {
+            let enabled: Vec<_> = [(*tags, "message-tags"), (*echo, "echo-message")]
+                .iter()
+                .filter(|(on, _)| *on)
+                .map(|(_, name)| *name)
+                .collect();
+            Some(format!(
+                ":{} CAP {} LIST :{}\r\n",
+                SERVER,
+                nick,
+                enabled.join(" ")
+            ))
+        }
+        Some("REQ") => {
+            let requested = params.get(1).copied().unwrap_or_default();
+            let names: Vec<_> = requested.split(' ').filter(|s| !s.is_empty()).collect();
+            // Capabilities are acknowledged all together or not at all.
+            let known = names
+                .iter()
+                .all(|name| CAPABILITIES.contains(&name.trim_start_matches('-')));
+            if !known {
+                return Some(format!(":{} CAP {} NAK :{}\r\n", SERVER, nick, requested));
+            }
+            for name in names {
+                let on = !name.starts_with('-');
+                match name.trim_start_matches('-') {
+                    "message-tags" => *tags = on,
+                    _ => *echo = on,
+                }
+            }
+            Some(format!(":{} CAP {} ACK :{}\r\n", SERVER, nick, requested))
+        }
+        _ => None,
+    }
+}
+
+/// Whether someone is connected with `nick` as their name.
+fn taken(hub: &Hub, nick: &str) -> bool {
+    hub.peers().iter().any(|(_, user)| self::nick(user) == nick)
+}
+
+fn nick_in_use(nick: &str) -> String {
+    format!(
+        ":{} 433 * {} :Nickname is already in use\r\n",
+        SERVER,
+        clean(nick)
+    )
+}
+
+/// Whether a nick can be used on IRC.
+fn valid_nick(nick: &str) -> bool {
+    !nick.is_empty()
+        && !nick.starts_with(|c: char| c == '#' || c == ':' || c.is_ascii_digit())
+        && !nick.contains(|c: char| c.is_whitespace() || c.is_control() || ",*?!@".contains(c))
+}
+
+/// Go through registration: capability negotiation, `NICK` and `USER`.
+/// Returns `None` if the client leaves before it's done.
+pub async fn register(
+    hub: &Hub,
+    lines: &mut FramedRead<Reader, LinesCodec>,
+    writer: &mut Writer,
+) -> Result<Option<Session>, Box<dyn Error + Send + Sync>> {
+    let mut nick = String::new();
+    let mut user = false;
+    let mut negotiating = false;
+    let (mut tags, mut echo) = (false, false);
+
+    loop {
+        if !nick.is_empty() && user && !negotiating {
+            // Someone could have taken the nick since it was chosen.
+            if !taken(hub, &nick) {
+                break;
+            }
+            writer.write_all(nick_in_use(&nick).as_bytes()).await?;
+            nick.clear();
+        }
+        let line = match lines.next().await {
+            Some(line) => line?,
+            None => return Ok(None),
+        };
+        let command = match split(&line) {
+            Some(command) => command,
+            None => continue,
+        };
+        let reply = match command.name.as_str() {
+            "CAP" => {
+                match command
+                    .params
+                    .first()
+                    .map(|s| s.to_ascii_uppercase())
+                    .as_deref()
+                {
+                    Some("END") => negotiating = false,
+                    Some(_) => negotiating = true,
+                    None => {}
+                }
+                capabilities(&nick, &mut tags, &mut echo, &command.params)
+            }
+            "NICK" => match command.params.first() {
+                Some(name) if valid_nick(name) && taken(hub, name) => Some(nick_in_use(name)),
+                Some(name) if valid_nick(name) => {
+                    nick = name.to_string();
+                    None
+                }
+                Some(name) => Some(format!(
+                    ":{} 432 * {} :Erroneous nickname\r\n",
+                    SERVER,
+                    clean(name)
+                )),
+                None => Some(format!(":{} 431 * :No nickname given\r\n", SERVER)),
+            },
+            "USER" => {
+                user = true;
+                None
+            }
+            "PASS" => None,
+            "PING" => Some(format!(
+                ":{} PONG {} :{}\r\n",
+                SERVER,
+                SERVER,
+                clean(command.params.first().copied().unwrap_or_default())
+            )),
+            "QUIT" => return Ok(None),
+            _ => Some(format!(":{} 451 * :You have not registered\r\n", SERVER)),
+        };
+        if let Some(reply) = reply {
+            writer.write_all(reply.as_bytes()).await?;
+        }
+    }
+
+    let session = Session {
+        nick,
+        tags,
+        echo,
+        rooms: HashMap::new(),
+    };
+    let mut welcome = session.numeric(
+        "001",
+        &format!(":Welcome to TagChat, {}", prefix(&session.nick)),
+    );
+    welcome += &session.numeric(
+        "002",
+        &format!(":Your host is {}, a TagChat server", SERVER),
+    );
+    welcome += &session.numeric("004", &format!("{} tagchat o o", SERVER));
+    welcome += &session.numeric(
+        "005",
+        "CHANTYPES=# NETWORK=TagChat :are supported by this server",
+    );
+    // Otherwise the message of the day follows.
+    if hub.config().motd.is_none() {
+        welcome += &session.numeric("422", ":MOTD File is missing");
+    }
+    writer.write_all(welcome.as_bytes()).await?;
+    Ok(Some(session))
+}
+
+/// Parse a line from a registered client.
+pub async fn parse(hub: &Hub, line: &str, session: &mut Session) -> Vec<Input> {
+    let command = match split(line) {
+        Some(command) => command,
+        None => return vec![],
+    };
+    let params = &command.params;
+    let first = params.first().copied().unwrap_or_default();
+    match command.name.as_str() {
+        "PRIVMSG" | "NOTICE" => {
+            let content = match params.get(1) {
+                Some(content) => content.to_string(),
+                None => return vec![Input::Reply(session.numeric("412", ":No text to send"))],
+            };
+            first
+                .split(',')
+                .map(|target| match target.strip_prefix('#') {
+                    Some(room) => Input::Frame(ClientFrame::Message {
+                        room: room.to_string(),
+                        content: content.clone(),
+                    }),
+                    None => Input::Frame(ClientFrame::Direct {
+                        to: target.to_string(),
+                        content: content.clone(),
+                    }),
+                })
+                .collect()
+        }
+        "JOIN" => {
+            let mut inputs = Vec::new();
+            for channel in first.split(',').filter(|s| !s.is_empty()) {
+                let room = match channel.strip_prefix('#') {
+                    Some(room) if is_channel(room) => room,
+                    _ => {
+                        let text = format!("{} :No such channel", channel);
+                        inputs.push(Input::Reply(session.numeric("403", &text)));
+                        continue;
+                    }
+                };
+                let joined = format!(":{} JOIN #{}\r\n", prefix(&session.nick), clean(room));
+                inputs.push(Input::Reply(joined));
+                // The recent messages come before the room's tags, which
+                // have to be known first to show them on the messages.
+                if let Some(ServerFrame::RoomTags { tags, tagged, .. }) =
+                    hub.room(room).tags().await
+                {
+                    session
+                        .rooms
+                        .insert(room.to_string(), Shared::new(&tags, &tagged));
+                }
+                let names = names(hub, room, Some(&session.nick)).await;
+                inputs.push(Input::Frame(ClientFrame::Join {
+                    room: room.to_string(),
+                    since: None,
+                }));
+                inputs.push(Input::Reply(names_reply(session, room, &names)));
+            }
+            inputs
+        }
+        "PART" => {
+            let mut inputs = Vec::new();
+            for room in first.split(',').filter_map(|s| s.strip_prefix('#')) {
+                session.rooms.remove(room);
+                let parted = format!(":{} PART #{}\r\n", prefix(&session.nick), clean(room));
+                inputs.extend([Input::Leave(room.to_string()), Input::Reply(parted)]);
+            }
+            inputs
+        }
+        "TAGMSG" => match command.tag("+tagchat/tags") {
+            Some(names) => edit_tags(hub, session, first, names, command.tag("+draft/reply")).await,
+            // Other tags, e.g. typing notifications, aren't passed on.
+            None => vec![],
+        },
+        "NAMES" => {
+            let mut out = String::new();
+            for room in first.split(',').filter_map(|s| s.strip_prefix('#')) {
+                out += &names_reply(session, room, &names(hub, room, None).await);
+            }
+            vec![Input::Reply(out)]
+        }
+        "WHO" => {
+            let users = match first.strip_prefix('#') {
+                Some(room) => names(hub, room, None).await,
+                None => {
+                    let mut users: Vec<_> = hub
+                        .peers()
+                        .into_iter()
+                        .map(|(_, user)| user)
+                        .filter(|user| user == first)
+                        .collect();
+                    users.dedup();
+                    users
+                }
+            };
+            let channel = if first.starts_with('#') { first } else { "*" };
+            let mut out = String::new();
+            for user in users {
+                let nick = nick(&user);
+                let text = format!(
+                    "{} {} {} {} {} H :0 {}",
+                    channel, nick, SERVER, SERVER, nick, user
+                );
+                out += &session.numeric("352", &text);
+            }
+            out += &session.numeric("315", &format!("{} :End of /WHO list", first));
+            vec![Input::Reply(out)]
+        }
+        "LIST" => {
+            let mut out = session.numeric("321", "Channel :Users  Name");
+            for (name, room) in hub.rooms() {
+                if !is_channel(&name) {
+                    continue;
+                }
+                if let Some(info) = room.info().await {
+                    let text = format!("#{} {} :", name, info.members.len());
+                    out += &session.numeric("322", &text);
+                }
+            }
+            out += &session.numeric("323", ":End of /LIST");
+            vec![Input::Reply(out)]
+        }
+        "MODE" if first.starts_with('#') => {
+            vec![Input::Reply(
+                session.numeric("324", &format!("{} +", first)),
+            )]
+        }
+        "MODE" => vec![],
+        "CAP" => {
+            let Session {
+                nick, tags, echo, ..
+            } = session;
+            capabilities(nick, tags, echo, params)
+                .map(Input::Reply)
+                .into_iter()
+                .collect()
+        }
+        "PING" => vec![Input::Reply(format!(
+            ":{} PONG {} :{}\r\n",
+            SERVER,
+            SERVER,
+            clean(first)
+        ))],
+        "PONG" => vec![Input::Frame(ClientFrame::Pong)],
+        "NICK" => vec![Input::Reply(
+            session.numeric("447", ":Nicknames can't be changed on TagChat"),
+        )],
+        "USER" => vec![Input::Reply(
+            session.numeric("462", ":You may not reregister"),
+        )],
+        "QUIT" => vec![Input::Quit],
+        name => vec![Input::Reply(
+            session.numeric("421", &format!("{} :Unknown command", name)),
+        )],
+    }
+}
+
+/// Give a message the shared tags named in `names`, for a `TAGMSG` from the
+/// client. `reply` is the message's `msgid`.
+async fn edit_tags(
+    hub: &Hub,
+    session: &Session,
+    channel: &str,
+    names: &str,
+    reply: Option<&str>,
+) -> Vec<Input> {
+    let room = channel.strip_prefix('#').unwrap_or_default();
+    let shared = match session.rooms.get(room) {
+        Some(shared) => shared,
+        None => {
+            let text = format!("{} :You're not on that channel", channel);
+            return vec![Input::Reply(session.numeric("442", &text))];
+        }
+    };
+    let message = match reply.and_then(|msgid| msgid.rsplit_once('/')?.1.parse().ok()) {
+        Some(message) => message,
+        None => {
+            return vec![Input::Reply(format!(
+                ":{} FAIL TAGMSG NEED_REPLY {} :{}\r\n",
+                SERVER,
+                clean(channel),
+                "+tagchat/tags needs the msgid of a message in +draft/reply"
+            ))]
+        }
+    };
+    let assign = |id: u64, on: bool| {
+        Input::Frame(ClientFrame::EditTags {
+            room: room.to_string(),
+            edit: TagEdit::Assign {
+                id,
+                messages: vec![message],
+                on,
+            },
+        })
+    };
+
+    let wanted: BTreeSet<_> = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    let mut inputs: Vec<_> = shared
+        .names
+        .iter()
+        .filter(|(id, name)| shared.has(**id, message) != wanted.contains(name.as_str()))
+        .map(|(id, name)| assign(*id, wanted.contains(name.as_str())))
+        .collect();
+    // Tags have to be created before they can be assigned, so the ones the
+    // room doesn't have yet are created right away.
+    for name in wanted {
+        if shared.names.values().any(|known| known == name) {
+            continue;
+        }
+        let create = TagEdit::Create {
+            name: name.to_string(),
+            color: NEW_TAG_COLOR,
+        };
+        match hub.room(room).edit_tags(session.nick.clone(), create).await {
+            Ok(ServerFrame::TagChanged { tag, .. }) => {
+                hub.tags_edited();
+                inputs.push(assign(tag.id, true));
+            }
+            Ok(_) => {}
+            Err(reason) => inputs.push(Input::Reply(format!(
+                ":{} NOTICE {} :{}\r\n",
+                SERVER,
+                session.nick,
+                clean(&reason)
+            ))),
+        }
+    }
+    inputs
+}
+
+/// Users in a room, with `me` added, since it's about to join.
+async fn names(hub: &Hub, room: &str, me: Option<&str>) -> Vec<String> {
+    let members = match hub.rooms().into_iter().find(|(name, _)| name == room) {
+        Some((_, handle)) => handle.info().await.map(|info| info.members),
+        None => None,
+    }
+    .unwrap_or_default();
+    let mut users: Vec<_> = hub
+        .peers()
+        .into_iter()
+        .filter(|(addr, _)| members.contains(addr))
+        .map(|(_, user)| user)
+        .chain(me.map(str::to_string))
+        .collect();
+    users.sort();
+    users.dedup();
+    users
+}
+
+fn names_reply(session: &Session, room: &str, users: &[String]) -> String {
+    let nicks: Vec<_> = users.iter().map(|user| nick(user)).collect();
+    let mut out = session.numeric("353", &format!("= #{} :{}", room, nicks.join(" ")));
+    out += &session.numeric("366", &format!("#{} :End of /NAMES list", room));
+    out
+}
+
+/// Write a frame as IRC lines. Returns `None` for frames IRC clients don't
+/// see. Changes of shared tags are also remembered in `session`.
+pub fn render(frame: &ServerFrame, session: &mut Session) -> Option<String> {
+    let mut out = String::new();
+    match frame {
+        ServerFrame::Message {
+            id,
+            sender,
+            room,
+            content,
+            ..
+        } => {
+            if !session.echo && *sender == session.nick {
+                return None;
+            }
+            let tags = if session.tags {
+                let mut tags = format!("@msgid={}", msgid(room, *id));
+                let names = session
+                    .rooms
+                    .get(room)
+                    .map(|shared| shared.of(*id))
+                    .unwrap_or_default();
+                if !names.is_empty() {
+                    let _ = write!(tags, ";+tagchat/tags={}", escape(&names));
+                }
+                tags + " "
+            } else {
+                String::new()
+            };
+            // IRC messages can't span lines.
+            for line in content.lines() {
+                let _ = write!(
+                    out,
+                    "{}:{} PRIVMSG #{} :{}\r\n",
+                    tags,
+                    prefix(sender),
+                    clean(room),
+                    clean(line)
+                );
+            }
+        }
+        ServerFrame::Direct { sender, content } => {
+            for line in content.lines() {
+                let _ = write!(
+                    out,
+                    ":{} PRIVMSG {} :{}\r\n",
+                    prefix(sender),
+                    session.nick,
+                    clean(line)
+                );
+            }
+        }
+        ServerFrame::Notice { text } => {
+            let _ = write!(
+                out,
+                ":{} NOTICE {} :{}\r\n",
+                SERVER,
+                session.nick,
+                clean(text)
+            );
+        }
+        ServerFrame::Announcement { text } => {
+            let text = clean(text);
+            let _ = write!(out, ":{} NOTICE {} :*** {}\r\n", SERVER, session.nick, text);
+        }
+        ServerFrame::Motd { text } => {
+            out += &session.numeric("375", &format!(":- {} Message of the day -", SERVER));
+            for line in text.lines() {
+                out += &session.numeric("372", &format!(":- {}", line));
+            }
+            out += &session.numeric("376", ":End of /MOTD command");
+        }
+        ServerFrame::Ping => {
+            let _ = write!(out, "PING :{}\r\n", SERVER);
+        }
+        ServerFrame::Shutdown { reason } => {
+            let _ = write!(out, "ERROR :{}\r\n", clean(reason));
+        }
+        ServerFrame::RoomTags { room, tags, tagged } => {
+            session
+                .rooms
+                .insert(room.clone(), Shared::new(tags, tagged));
+        }
+        ServerFrame::TagChanged { room, tag } => {
+            let shared = session.rooms.get_mut(room)?;
+            let old = shared.names.insert(tag.id, tag.name.clone());
+            // Only renaming a tag changes what its messages look like.
+            if session.tags && old.is_some_and(|old| old != tag.name) {
+                for message in shared.tagged.get(&tag.id).into_iter().flatten() {
+                    out += &tagmsg(room, *message, shared);
+                }
+            }
+        }
+        ServerFrame::TagDeleted { room, id } => {
+            let shared = session.rooms.get_mut(room)?;
+            shared.names.remove(id);
+            let messages = shared.tagged.remove(id).unwrap_or_default();
+            if session.tags {
+                for message in messages {
+                    out += &tagmsg(room, message, shared);
+                }
+            }
+        }
+        ServerFrame::Tagged {
+            room,
+            id,
+            messages,
+            on,
+        } => {
+            let shared = session.rooms.get_mut(room)?;
+            let tagged = shared.tagged.entry(*id).or_default();
+            for message in messages {
+                if *on {
+                    tagged.insert(*message);
+                } else {
+                    tagged.remove(message);
+                }
+            }
+            if tagged.is_empty() {
+                shared.tagged.remove(id);
+            }
+            if session.tags {
+                for message in messages {
+                    out += &tagmsg(room, *message, shared);
+                }
+            }
+        }
+        ServerFrame::Pong | ServerFrame::Welcome { .. } | ServerFrame::AdminReply { .. } => {
+            return None
+        }
+    }
+    Some(out).filter(|out| !out.is_empty())
+}
+
+/// `render` for a frame which is already encoded, as they are in the outbox.
+pub fn render_line(line: &[u8], session: &mut Session) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    render(&protocol::decode(line.trim_end()).ok()?, session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::config::Config;
+    use crate::server::listener::PeerAddr;
+    use crate::server::outbox::{Outbox, SlowConsumerPolicy};
+    use std::sync::Arc;
+    use tokio_util::sync::CancellationToken;
+
+    fn hub() -> Hub {
+        let config = Config {
+            data_dir: std::env::temp_dir().join("tagchat-irc-tests-nothing-saved"),
+            ..Default::default()
+        };
+        Hub::new(config, Box::new(|| Ok(Config::default()))).unwrap()
+    }
+
+    fn connect(hub: &Hub, number: u64, username: &str) {
+        let outbox = Arc::new(Outbox::new(8, SlowConsumerPolicy::DropOldest));
+        let addr = PeerAddr::Unix(number);
+        hub.add_peer(addr, username.into(), outbox, CancellationToken::new());
+    }
+
+    fn session(nick: &str, tags: bool) -> Session {
+        Session {
+            nick: nick.into(),
+            tags,
+            echo: false,
+            rooms: HashMap::new(),
+        }
+    }
+
+    fn replies(inputs: &[Input]) -> String {
+        inputs
+            .iter()
+            .filter_map(|input| match input {
+                Input::Reply(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn message(room: &str, sender: &str, content: &str) -> ServerFrame {
+        ServerFrame::Message {
+            id: 7,
+            sender: sender.into(),
+            room: room.into(),
+            content: content.into(),
+            sent: None,
+        }
+    }
+
+    #[test]
+    fn split_reads_tags_prefix_and_trailing() {
+        let line = "@+draft/reply=a\\sb/3;+tagchat/tags=bug :me!me@x tagmsg #room :one two\r";
+        let command = split(line).unwrap();
+        assert_eq!(command.name, "TAGMSG");
+        assert_eq!(command.params, ["#room", "one two"]);
+        assert_eq!(command.tag("+draft/reply"), Some("a b/3"));
+        assert_eq!(command.tag("+tagchat/tags"), Some("bug"));
+        assert!(split("   ").is_none());
+    }
+
+    #[test]
+    fn escaping_is_undone() {
+        let value = "a; b\\c\r\n";
+        assert_eq!(escape(value), "a\\:\\sb\\\\c\\r\\n");
+        assert_eq!(unescape(&escape(value)), value);
+    }
+
+    #[test]
+    fn line_breaks_from_other_clients_are_replaced() {
+        let mut session = session("bob", false);
+        let frame = message("room", "eve\r\nQUIT", "hi\r:tagchat NOTICE bob :owned\0!");
+        let out = render(&frame, &mut session).unwrap();
+        assert_eq!(
+            out,
+            ":eve__QUIT!eve__QUIT@tagchat PRIVMSG #room :hi :tagchat NOTICE bob :owned !\r\n"
+        );
+
+        let notice = ServerFrame::Notice {
+            text: "a\rb\nc".into(),
+        };
+        let out = render(&notice, &mut session).unwrap();
+        assert_eq!(out, ":tagchat NOTICE bob :a b c\r\n");
+    }
+
+    #[test]
+    fn messages_are_split_into_lines_with_tags() {
+        let mut session = session("bob", true);
+        let shared = SharedTag {
+            id: 1,
+            name: "bug".into(),
+            color: NEW_TAG_COLOR,
+            owner: "alice".into(),
+        };
+        let tagged = BTreeMap::from([(1, BTreeSet::from([7]))]);
+        let tags = ServerFrame::RoomTags {
+            room: "room".into(),
+            tags: vec![shared],
+            tagged,
+        };
+        assert!(render(&tags, &mut session).is_none());
+
+        let out = render(&message("room", "a b", "one\r\ntwo"), &mut session).unwrap();
+        let tags = "@msgid=room/7;+tagchat/tags=bug";
+        assert_eq!(
+            out,
+            format!(
+                "{0} :a_b!a_b@tagchat PRIVMSG #room :one\r\n\
+                 {0} :a_b!a_b@tagchat PRIVMSG #room :two\r\n",
+                tags
+            )
+        );
+        // Without echo-message, the client's own messages aren't sent back.
+        assert!(render(&message("room", "bob", "mine"), &mut session).is_none());
+    }
+
+    #[test]
+    fn tag_changes_become_tagmsgs() {
+        let mut session = session("bob", true);
+        let tags = ServerFrame::RoomTags {
+            room: "room".into(),
+            tags: vec![],
+            tagged: BTreeMap::new(),
+        };
+        render(&tags, &mut session);
+        let created = ServerFrame::TagChanged {
+            room: "room".into(),
+            tag: SharedTag {
+                id: 2,
+                name: "ui".into(),
+                color: NEW_TAG_COLOR,
+                owner: "alice".into(),
+            },
+        };
+        assert!(render(&created, &mut session).is_none());
+        let tagged = ServerFrame::Tagged {
+            room: "room".into(),
+            id: 2,
+            messages: vec![7],
+            on: true,
+        };
+        assert_eq!(
+            render(&tagged, &mut session).unwrap(),
+            "@+draft/reply=room/7;+tagchat/tags=ui :tagchat TAGMSG #room\r\n"
+        );
+        let deleted = ServerFrame::TagDeleted {
+            room: "room".into(),
+            id: 2,
+        };
+        assert_eq!(
+            render(&deleted, &mut session).unwrap(),
+            "@+draft/reply=room/7;+tagchat/tags= :tagchat TAGMSG #room\r\n"
+        );
+    }
+
+    #[test]
+    fn capabilities_are_acknowledged_together() {
+        let (mut tags, mut echo) = (false, false);
+        let ls = capabilities("", &mut tags, &mut echo, &["LS", "302"]).unwrap();
+        assert_eq!(ls, ":tagchat CAP * LS :message-tags echo-message\r\n");
+
+        let nak = capabilities("bob", &mut tags, &mut echo, &["REQ", "message-tags sasl"]);
+        assert_eq!(nak.unwrap(), ":tagchat CAP bob NAK :message-tags sasl\r\n");
+        assert!(!tags && !echo);
+
+        let ack = capabilities("bob", &mut tags, &mut echo, &["req", "message-tags"]);
+        assert_eq!(ack.unwrap(), ":tagchat CAP bob ACK :message-tags\r\n");
+        assert!(tags && !echo);
+
+        capabilities(
+            "bob",
+            &mut tags,
+            &mut echo,
+            &["REQ", "-message-tags echo-message"],
+        );
+        assert!(!tags && echo);
+        let list = capabilities("bob", &mut tags, &mut echo, &["LIST"]).unwrap();
+        assert_eq!(list, ":tagchat CAP bob LIST :echo-message\r\n");
+    }
+
+    #[tokio::test]
+    async fn nicks_of_connected_users_are_taken() {
+        let hub = hub();
+        connect(&hub, 1, "ann lee");
+        assert!(taken(&hub, "ann_lee"));
+        assert!(!taken(&hub, "ann"));
+        assert!(!valid_nick("#ann") && !valid_nick("ann\0") && !valid_nick("1ann"));
+        assert!(valid_nick("ann"));
+    }
+
+    #[tokio::test]
+    async fn channels_are_the_names_of_rooms() {
+        let hub = hub();
+        let mut session = session("bob", false);
+
+        let inputs = parse(&hub, "JOIN #a_b,#a\rb", &mut session).await;
+        let joins: Vec<_> = inputs
+            .iter()
+            .filter_map(|input| match input {
+                Input::Frame(ClientFrame::Join { room, .. }) => Some(room.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(joins, ["a_b"]);
+        assert!(replies(&inputs).contains(":tagchat 403 bob #a b :No such channel\r\n"));
+
+        // Rooms IRC can't name aren't listed.
+        hub.room("a b");
+        let list = replies(&parse(&hub, "LIST", &mut session).await);
+        assert!(list.contains("322 bob #a_b 0 :"));
+        assert!(!list.contains("#a b"));
+    }
+
+    #[tokio::test]
+    async fn tagmsg_edits_shared_tags() {
+        let hub = hub();
+        let mut session = session("bob", true);
+
+        let tagmsg = "@+draft/reply=room/7;+tagchat/tags=bug TAGMSG #room";
+        let inputs = parse(&hub, tagmsg, &mut session).await;
+        assert!(replies(&inputs).contains(" 442 bob #room "));
+
+        parse(&hub, "JOIN #room", &mut session).await;
+        let inputs = parse(&hub, "@+tagchat/tags=bug TAGMSG #room", &mut session).await;
+        assert!(replies(&inputs).starts_with(":tagchat FAIL TAGMSG NEED_REPLY #room :"));
+
+        // A tag the room doesn't have is created, then assigned.
+        let inputs = parse(&hub, tagmsg, &mut session).await;
+        match inputs.as_slice() {
+            [Input::Frame(ClientFrame::EditTags {
+                room,
+                edit: TagEdit::Assign { messages, on, .. },
+            })] => {
+                assert_eq!(room, "room");
+                assert_eq!(messages, &[7]);
+                assert!(*on);
+            }
+            _ => panic!("expected an assignment"),
+        }
+        match hub.room("room").tags().await {
+            Some(ServerFrame::RoomTags { tags, .. }) => {
+                assert_eq!(tags.len(), 1);
+                assert_eq!(
+                    (tags[0].name.as_str(), tags[0].owner.as_str()),
+                    ("bug", "bob")
+                );
+            }
+            _ => panic!("expected the tags of the room"),
+        }
+
+        // Other tags aren't passed on.
+        assert!(parse(&hub, "@+typing=active TAGMSG #room", &mut session)
+            .await
+            .is_empty());
+    }
+}
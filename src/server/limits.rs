@@ -0,0 +1,258 @@
+//! Flood protection.
+//!
+//! Every connection and every account (user name) has a token bucket. Each
+//! message takes a token from both. A client which runs out of tokens is first
+//! throttled, then its messages are dropped with a warning and finally it gets
+//! disconnected. The strikes are forgiven once the client stays quiet long
+//! enough for its bucket to fill up again.
+
+use std::time::{Duration, Instant};
+
+use super::config::{Limits, RateLimit};
+
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    pub fn new(rate: &RateLimit) -> Self {
+        TokenBucket {
+            capacity: rate.burst as f64,
+            tokens: rate.burst as f64,
+            per_second: rate.per_second,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Switch to a new rate, keeping the tokens collected so far up to the new
+    /// burst.
+    pub fn set_rate(&mut self, rate: &RateLimit) {
+        self.refill();
+        self.capacity = rate.burst as f64;
+        self.per_second = rate.per_second;
+        self.tokens = self.tokens.min(self.capacity);
+    }
+
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    /// How long to wait for a token, zero if one is available right away.
+    pub fn wait_time(&mut self) -> Duration {
+        self.refill();
+        if self.tokens >= 1. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1. - self.tokens) / self.per_second)
+        }
+    }
+
+    /// Take a token. The bucket may go into debt, which later refills pay off.
+    pub fn take(&mut self) {
+        self.refill();
+        self.tokens -= 1.;
+    }
+}
+
+/// What to do with a message which has just arrived.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Wait before handling the message.
+    Throttle(Duration),
+    /// Drop the message and warn the client.
+    Warn,
+    Disconnect,
+}
+
+/// Flood protection state of a single connection.
+pub struct FloodGuard {
+    bucket: TokenBucket,
+    strikes: u32,
+}
+
+impl FloodGuard {
+    pub fn new(limits: &Limits) -> Self {
+        FloodGuard {
+            bucket: TokenBucket::new(&limits.connection_rate),
+            strikes: 0,
+        }
+    }
+
+    /// Decide what to do with the next message. Doesn't take any tokens, call
+    /// `take` once the message is accepted. The rates come from `limits`, so
+    /// that reloading the configuration changes them.
+    pub fn check(&mut self, limits: &Limits, account: &mut TokenBucket) -> Verdict {
+        self.bucket.set_rate(&limits.connection_rate);
+        account.set_rate(&limits.account_rate);
+        if self.bucket.is_full() {
+            self.strikes = 0;
+        }
+
+        let wait = self.bucket.wait_time().max(account.wait_time());
+        if wait.is_zero() {
+            return Verdict::Allow;
+        }
+
+        self.strikes += 1;
+        if self.strikes > limits.disconnect_after {
+            Verdict::Disconnect
+        } else if self.strikes > limits.warn_after {
+            Verdict::Warn
+        } else {
+            Verdict::Throttle(wait)
+        }
+    }
+
+    pub fn take(&mut self, account: &mut TokenBucket) {
+        self.bucket.take();
+        account.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(burst: u32, per_second: f64) -> RateLimit {
+        RateLimit { burst, per_second }
+    }
+
+    /// Pretend that `elapsed` has passed since the bucket was last refilled.
+    fn rewind(bucket: &mut TokenBucket, elapsed: Duration) {
+        bucket.updated -= elapsed;
+    }
+
+    fn limits(connection: RateLimit, account: RateLimit) -> Limits {
+        Limits {
+            warn_after: 2,
+            disconnect_after: 4,
+            connection_rate: connection,
+            account_rate: account,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst() {
+        let mut bucket = TokenBucket::new(&rate(3, 1.));
+        for _ in 0..3 {
+            assert_eq!(bucket.wait_time(), Duration::ZERO);
+            bucket.take();
+        }
+        assert!(bucket.wait_time() > Duration::ZERO);
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(&rate(2, 4.));
+        bucket.take();
+        bucket.take();
+        let wait = bucket.wait_time();
+        assert!(wait > Duration::from_millis(100) && wait <= Duration::from_millis(250));
+
+        rewind(&mut bucket, Duration::from_millis(250));
+        assert_eq!(bucket.wait_time(), Duration::ZERO);
+        assert!(!bucket.is_full());
+
+        // It never holds more than the burst.
+        rewind(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.is_full());
+        bucket.take();
+        bucket.take();
+        assert!(bucket.wait_time() > Duration::ZERO);
+    }
+
+    #[test]
+    fn bucket_pays_off_debt() {
+        let mut bucket = TokenBucket::new(&rate(1, 1.));
+        bucket.take();
+        bucket.take();
+        rewind(&mut bucket, Duration::from_secs(1));
+        assert!(bucket.wait_time() > Duration::ZERO);
+        rewind(&mut bucket, Duration::from_secs(1));
+        assert_eq!(bucket.wait_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn new_rate_keeps_tokens_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(&rate(10, 1.));
+        bucket.set_rate(&rate(2, 1.));
+        assert!(bucket.is_full());
+        bucket.take();
+        bucket.take();
+        assert!(bucket.wait_time() > Duration::ZERO);
+    }
+
+    #[test]
+    fn guard_throttles_then_warns_then_disconnects() {
+        let limits = limits(rate(1, 0.01), rate(100, 100.));
+        let mut account = TokenBucket::new(&limits.account_rate);
+        let mut guard = FloodGuard::new(&limits);
+
+        assert_eq!(guard.check(&limits, &mut account), Verdict::Allow);
+        guard.take(&mut account);
+
+        let verdicts: Vec<_> = (0..5).map(|_| guard.check(&limits, &mut account)).collect();
+        assert!(matches!(verdicts[0], Verdict::Throttle(wait) if wait > Duration::ZERO));
+        assert!(matches!(verdicts[1], Verdict::Throttle(_)));
+        assert_eq!(
+            verdicts[2..],
+            [Verdict::Warn, Verdict::Warn, Verdict::Disconnect]
+        );
+    }
+
+    #[test]
+    fn guard_forgives_strikes_once_the_bucket_is_full() {
+        let limits = limits(rate(1, 1.), rate(100, 100.));
+        let mut account = TokenBucket::new(&limits.account_rate);
+        let mut guard = FloodGuard::new(&limits);
+
+        guard.take(&mut account);
+        for _ in 0..3 {
+            guard.check(&limits, &mut account);
+        }
+        assert_eq!(guard.check(&limits, &mut account), Verdict::Warn);
+
+        rewind(&mut guard.bucket, Duration::from_secs(1));
+        assert_eq!(guard.check(&limits, &mut account), Verdict::Allow);
+        guard.take(&mut account);
+        assert!(matches!(
+            guard.check(&limits, &mut account),
+            Verdict::Throttle(_)
+        ));
+    }
+
+    #[test]
+    fn account_limits_all_connections_together() {
+        let limits = limits(rate(10, 1.), rate(2, 0.01));
+        let mut account = TokenBucket::new(&limits.account_rate);
+        let mut first = FloodGuard::new(&limits);
+        let mut second = FloodGuard::new(&limits);
+
+        for guard in [&mut first, &mut second] {
+            assert_eq!(guard.check(&limits, &mut account), Verdict::Allow);
+            guard.take(&mut account);
+        }
+        assert!(matches!(
+            first.check(&limits, &mut account),
+            Verdict::Throttle(_)
+        ));
+        assert!(matches!(
+            second.check(&limits, &mut account),
+            Verdict::Throttle(_)
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+//! Sockets the clients connect to.
+//!
+//! Besides TCP, on Unix the server can listen on a Unix domain socket, meant
+//! for bots and tools running on the same host. Who can connect to it is
+//! decided by the permissions of the socket file. With the `quic` feature it
+//! can also accept QUIC connections, see `quic`. TCP connections can go over
+//! TLS, see `tls`.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+/// Read half of a client's connection.
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Write half of a client's connection.
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where a connection comes from. Identifies the connection while it lasts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    #[cfg(feature = "quic")]
+    Quic(SocketAddr),
+    /// Connections to the Unix socket have no address, so they are numbered.
+    Unix(u64),
+}
+
+impl PeerAddr {
+    /// The IP address of a TCP or QUIC connection.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            #[cfg(feature = "quic")]
+            PeerAddr::Quic(addr) => Some(addr.ip()),
+            PeerAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(feature = "quic")]
+            PeerAddr::Quic(addr) => write!(f, "quic:{}", addr),
+            PeerAddr::Unix(number) => write!(f, "unix#{}", number),
+        }
+    }
+}
+
+/// A connection ready to be served.
+pub struct Connection {
+    pub reader: Reader,
+    pub writer: Writer,
+    /// Stream for the history replayed when joining rooms. Without one, the
+    /// history goes through `writer` together with live messages.
+    pub history: Option<Writer>,
+    pub linger: Linger,
+}
+
+/// Keeps a connection open after the server is done with it, until the
+/// client closes it.
+#[derive(Default)]
+pub struct Linger(#[cfg(feature = "quic")] pub Option<quinn::Connection>);
+
+impl Linger {
+    /// Wait, at most `timeout`, for the client to close the connection.
+    #[cfg_attr(not(feature = "quic"), allow(unused_variables))]
+    pub async fn wait(self, timeout: Duration) {
+        #[cfg(feature = "quic")]
+        if let Some(connection) = self.0 {
+            let _ = tokio::time::timeout(timeout, connection.closed()).await;
+        }
+    }
+}
+
+/// A connection which was just accepted.
+pub enum Incoming {
+    Stream(Reader, Writer),
+    /// The TLS handshake hasn't happened yet.
+    Tls(TlsAcceptor, TcpStream),
+    /// The QUIC handshake hasn't happened yet.
+    #[cfg(feature = "quic")]
+    Quic(Box<quinn::Incoming>),
+}
+
+impl Incoming {
+    /// Do what's left to set up the connection, e.g. a handshake.
+    pub async fn establish(self) -> io::Result<Connection> {
+        match self {
+            Incoming::Stream(reader, writer) => Ok(Connection {
+                reader,
+                writer,
+                history: None,
+                linger: Linger::default(),
+            }),
+            Incoming::Tls(acceptor, stream) => {
+                let (reader, writer) = tokio::io::split(acceptor.accept(stream).await?);
+                Ok(Connection {
+                    reader: Box::new(reader),
+                    writer: Box::new(writer),
+                    history: None,
+                    linger: Linger::default(),
+                })
+            }
+            #[cfg(feature = "quic")]
+            Incoming::Quic(incoming) => super::quic::establish(*incoming).await,
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        /// Number of the next connection.
+        next: u64,
+    },
+    #[cfg(feature = "quic")]
+    Quic(quinn::Endpoint),
+}
+
+impl Listener {
+    pub async fn accept(&mut self) -> io::Result<(Incoming, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                let incoming = Incoming::Stream(Box::new(reader), Box::new(writer));
+                Ok((incoming, PeerAddr::Tcp(addr)))
+            }
+            Listener::Tls(listener, acceptor) => {
+                let (stream, addr) = listener.accept().await?;
+                let incoming = Incoming::Tls(acceptor.clone(), stream);
+                Ok((incoming, PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, next } => {
+                let (stream, _) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                *next += 1;
+                let incoming = Incoming::Stream(Box::new(reader), Box::new(writer));
+                Ok((incoming, PeerAddr::Unix(*next)))
+            }
+            #[cfg(feature = "quic")]
+            Listener::Quic(endpoint) => match endpoint.accept().await {
+                Some(incoming) => {
+                    let addr = incoming.remote_address();
+                    Ok((Incoming::Quic(Box::new(incoming)), PeerAddr::Quic(addr)))
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "the QUIC endpoint was closed",
+                )),
+            },
+        }
+    }
+
+    /// Listen on a Unix socket at `path`, with the socket file's permissions
+    /// set to `mode`. A socket left over from a previous run is replaced.
+    ///
+    /// The socket is created in a directory only the server can enter and
+    /// moved to `path` once it has its permissions, so that nobody can
+    /// connect before that.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &std::path::Path, mode: u32) -> io::Result<Listener> {
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the socket path has no file name",
+            )
+        })?;
+        let private = path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let bind = || -> io::Result<_> {
+            let hidden = private.join("socket");
+            let listener = tokio::net::UnixListener::bind(&hidden)?;
+            std::fs::set_permissions(&hidden, std::fs::Permissions::from_mode(mode))?;
+            std::fs::rename(&hidden, path)?;
+            Ok(listener)
+        };
+        let listener = bind();
+        let _ = std::fs::remove_dir_all(&private);
+        Ok(Listener::Unix {
+            listener: listener?,
+            next: 0,
+        })
+    }
+}
@@ -0,0 +1,248 @@
+//! Counters describing what the server does, served over HTTP in the
+//! Prometheus text format.
+//!
+//! The counters only ever grow, rates such as messages per second are left to
+//! Prometheus, e.g. `rate(tagchat_messages_received_total[1m])`.
+
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::hub::Hub;
+use super::limits::Verdict;
+
+/// Upper bounds of the broadcast latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.,
+];
+
+/// Requests longer than this are not worth reading.
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// How long a client has to send its request and read the answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Chat messages accepted from clients.
+    messages_received: AtomicU64,
+    /// Frames written to clients.
+    frames_sent: AtomicU64,
+    /// Connections closed because of the connection limits.
+    connections_rejected: AtomicU64,
+    /// Connections which didn't start with a valid login frame.
+    auth_failures: AtomicU64,
+    throttled: AtomicU64,
+    warned: AtomicU64,
+    flood_disconnects: AtomicU64,
+    /// Time between reading a message and queueing it for every member of the
+    /// room.
+    latency: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Number of observations in each bucket, the last one being `+Inf`.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// Sum of the observations, in microseconds.
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub fn message_received(&self) {
+        increment(&self.messages_received);
+    }
+
+    pub fn frame_sent(&self) {
+        increment(&self.frames_sent);
+    }
+
+    pub fn connection_rejected(&self) {
+        increment(&self.connections_rejected);
+    }
+
+    pub fn auth_failure(&self) {
+        increment(&self.auth_failures);
+    }
+
+    /// Count a rate limit hit.
+    pub fn rate_limited(&self, verdict: &Verdict) {
+        match verdict {
+            Verdict::Allow => {}
+            Verdict::Throttle(_) => increment(&self.throttled),
+            Verdict::Warn => increment(&self.warned),
+            Verdict::Disconnect => increment(&self.flood_disconnects),
+        }
+    }
+
+    pub fn broadcast_latency(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        increment(&self.latency.buckets[bucket]);
+        self.latency
+            .sum
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        increment(&self.latency.count);
+    }
+
+    /// All metrics in the Prometheus text format.
+    fn render(&self, hub: &Hub) -> String {
+        let stats = hub.stats();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        for (name, kind, help, value) in [
+            (
+                "tagchat_peers",
+                "gauge",
+                "Logged in clients.",
+                stats.peers as u64,
+            ),
+            ("tagchat_rooms", "gauge", "Rooms.", stats.rooms as u64),
+            (
+                "tagchat_queued_messages",
+                "gauge",
+                "Messages waiting to be sent, in all queues.",
+                stats.queued as u64,
+            ),
+            (
+                "tagchat_longest_queue",
+                "gauge",
+                "Messages waiting in the longest queue.",
+                stats.longest_queue as u64,
+            ),
+            (
+                "tagchat_dropped_messages_total",
+                "counter",
+                "Messages dropped from full queues.",
+                stats.dropped,
+            ),
+            (
+                "tagchat_messages_received_total",
+                "counter",
+                "Chat messages received from clients.",
+                load(&self.messages_received),
+            ),
+            (
+                "tagchat_frames_sent_total",
+                "counter",
+                "Frames sent to clients.",
+                load(&self.frames_sent),
+            ),
+            (
+                "tagchat_connections_rejected_total",
+                "counter",
+                "Connections over the connection limits.",
+                load(&self.connections_rejected),
+            ),
+            (
+                "tagchat_auth_failures_total",
+                "counter",
+                "Connections without a valid login.",
+                load(&self.auth_failures),
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let name = "tagchat_rate_limited_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Messages over the rate limits, by what was done about them.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (action, counter) in [
+            ("throttle", &self.throttled),
+            ("warn", &self.warned),
+            ("disconnect", &self.flood_disconnects),
+        ] {
+            let _ = writeln!(out, "{}{{action=\"{}\"}} {}", name, action, load(counter));
+        }
+
+        let name = "tagchat_broadcast_latency_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time from reading a message to queueing it for the room.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, bucket) in self.latency.buckets.iter().enumerate() {
+            cumulative += load(bucket);
+            let bound = match LATENCY_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".into(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let sum = load(&self.latency.sum) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, load(&self.latency.count));
+        out
+    }
+}
+
+/// Serve `GET /metrics` on the given address.
+pub async fn serve(listener: TcpListener, hub: Arc<Hub>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // E.g. out of file descriptors, try again in a moment.
+                tracing::warn!(error = %e, "couldn't accept a metrics request");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let hub = hub.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &hub)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::debug!(error = %e, "metrics request failed"),
+                Err(_) => tracing::debug!("metrics request timed out"),
+            }
+        });
+    }
+}
+
+/// Answer a single HTTP request and close the connection.
+async fn respond(mut stream: TcpStream, hub: &Hub) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > MAX_REQUEST_LENGTH {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let (status, body) = if request.starts_with(b"GET /metrics ") {
+        ("200 OK", hub.metrics.render(hub))
+    } else {
+        ("404 Not Found", "Not found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
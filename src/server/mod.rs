@@ -0,0 +1,932 @@
+//! The chat server. See `src/bin/server.rs` for the entry point.
+
+mod admin;
+pub mod config;
+mod history;
+mod hub;
+mod irc;
+pub mod limits;
+mod listener;
+mod metrics;
+pub mod outbox;
+#[cfg(feature = "quic")]
+mod quic;
+mod room;
+mod shared_tags;
+mod storage;
+mod text;
+mod tls;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::Instrument;
+
+use bytes::Bytes;
+use futures::future::try_join_all;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{self, ClientFrame, ServerFrame};
+use config::{Config, ConfigError, Heartbeat, Limits};
+use hub::Hub;
+use limits::{FloodGuard, TokenBucket, Verdict};
+use listener::{Listener, PeerAddr, Reader, Writer};
+use metrics::Metrics;
+use outbox::{Next, Outbox, SlowConsumerPolicy};
+use room::{Replay, Room};
+
+/// How long a client has to finish setting up its connection, e.g. the QUIC
+/// handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listen on every address from the config, and on the Unix socket, the QUIC
+/// address and the IRC address if there are any, and serve clients until `shutdown`
+/// completes or an accept loop fails.
+///
+/// Shutting down stops accepting connections, tells the clients that the
+/// server is going down, gives them `shutdown_timeout` seconds to receive what
+/// is still queued for them and saves the history of the rooms.
+///
+/// `reload` reads the configuration again when an operator asks for it.
+pub async fn run(
+    config: Config,
+    reload: impl Fn() -> Result<Config, ConfigError> + Send + Sync + 'static,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error>> {
+    // The data directory is created on the first start.
+    std::fs::create_dir_all(&config.data_dir)?;
+
+    // Create the shared state. This is how the peers find each other and the
+    // rooms.
+    //
+    // The server task will hold a handle to this. For every new client, the
+    // `hub` handle is cloned and passed into the task that processes the
+    // client connection.
+    let hub = Arc::new(Hub::new(config.clone(), Box::new(reload))?);
+
+    // Cancelled once the server starts shutting down.
+    let stop = CancellationToken::new();
+    // Every connection holds a sender. Once all of them are dropped, the
+    // receiver knows the connections are closed.
+    let (closed_tx, mut closed_rx) = mpsc::channel::<()>(1);
+
+    // Bind a TCP listener to every socket address.
+    //
+    // Note that this is the Tokio TcpListener, which is fully async.
+    let acceptor = config.tls.as_ref().map(tls::acceptor).transpose()?;
+    let mut listeners = Vec::new();
+    for addr in config.listen_addrs()? {
+        let listener = TcpListener::bind(&addr).await?;
+        let listener = match &acceptor {
+            Some(acceptor) => {
+                tracing::info!(%addr, "server running over TLS");
+                Listener::Tls(listener, acceptor.clone())
+            }
+            None => {
+                tracing::info!(%addr, "server running");
+                Listener::Tcp(listener)
+            }
+        };
+        listeners.push((listener, Dialect::TagChat));
+    }
+    #[cfg(unix)]
+    if let Some(unix) = &config.unix_socket {
+        let listener = Listener::bind_unix(&unix.path, unix.mode)?;
+        listeners.push((listener, Dialect::TagChat));
+        tracing::info!(path = %unix.path.display(), "server running");
+    }
+    #[cfg(feature = "quic")]
+    if let Some(settings) = &config.quic {
+        let endpoint = quic::bind(settings, config.tls.as_ref(), &config.data_dir)?;
+        listeners.push((Listener::Quic(endpoint), Dialect::TagChat));
+        tracing::info!(addr = %settings.listen, "server running over QUIC");
+    }
+    if let Some(settings) = &config.irc {
+        let listener = TcpListener::bind(settings.listen).await?;
+        listeners.push((Listener::Tcp(listener), Dialect::Irc));
+        tracing::info!(addr = %settings.listen, "server running for IRC clients");
+    }
+
+    if let Some(metrics) = &config.metrics {
+        let listener = TcpListener::bind(metrics.listen).await?;
+        tracing::info!(addr = %metrics.listen, "serving metrics at /metrics");
+        tokio::spawn(metrics::serve(listener, hub.clone()));
+    }
+
+    if config.stats_interval > 0 {
+        tokio::spawn(log_stats(
+            hub.clone(),
+            Duration::from_secs(config.stats_interval),
+        ));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(hub.clone()));
+
+    let saver = tokio::spawn(save_tags(hub.clone(), stop.clone()));
+
+    let accept_loops = listeners.into_iter().map(|(listener, dialect)| {
+        tokio::spawn(accept(
+            listener,
+            dialect,
+            hub.clone(),
+            stop.clone(),
+            closed_tx.clone(),
+        ))
+    });
+    let result: io::Result<()> = tokio::select! {
+        result = try_join_all(accept_loops) => match result {
+            Ok(results) => results.into_iter().collect(),
+            Err(e) => Err(e.into()),
+        },
+        _ = shutdown => Ok(()),
+    };
+    tracing::info!("shutting down");
+
+    // Queue the notice before the connections learn about the shutdown, so
+    // that it's among the messages they send before closing.
+    let frame = ServerFrame::Shutdown {
+        reason: "the server is shutting down".into(),
+    };
+    hub.broadcast(None, encode_line(&frame));
+    stop.cancel();
+
+    drop(closed_tx);
+    let config = hub.config();
+    let deadline = Duration::from_secs(config.shutdown_timeout) + Duration::from_secs(1);
+    if tokio::time::timeout(deadline, closed_rx.recv())
+        .await
+        .is_err()
+    {
+        tracing::warn!("some connections didn't close in time");
+    }
+
+    #[cfg(unix)]
+    if let Some(unix) = &config.unix_socket {
+        let _ = std::fs::remove_file(&unix.path);
+    }
+
+    // Saving twice at once would mix up the file.
+    let _ = saver.await;
+    history::save(&config.data_dir, &hub.snapshot().await)?;
+    tracing::info!("history saved");
+    Ok(result?)
+}
+
+/// What the clients of a listener speak.
+#[derive(Clone, Copy)]
+enum Dialect {
+    /// The JSON protocol, or plain text, see `text`.
+    TagChat,
+    /// See `irc`.
+    Irc,
+}
+
+/// Accept connections on a single listener until `stop` is cancelled.
+async fn accept(
+    mut listener: Listener,
+    dialect: Dialect,
+    hub: Arc<Hub>,
+    stop: CancellationToken,
+    closed: mpsc::Sender<()>,
+) -> io::Result<()> {
+    loop {
+        // Asynchronously wait for an inbound connection.
+        let (incoming, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = stop.cancelled() => return Ok(()),
+        };
+
+        if let Err(reason) = hub.connect(addr.ip(), &hub.config().limits) {
+            tracing::warn!(%addr, reason, "rejected connection");
+            hub.metrics.connection_rejected();
+            continue;
+        }
+
+        // Clone a handle to the `Hub` for the new connection.
+        let hub = Arc::clone(&hub);
+        let stop = stop.clone();
+        let closed = closed.clone();
+
+        // Spawn our handler to be run asynchronously. Everything it logs is
+        // tagged with the address and, once it logs in, the user.
+        let span = tracing::info_span!("connection", %addr, user = tracing::field::Empty);
+        tokio::spawn(
+            async move {
+                tracing::debug!("accepted connection");
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.establish()).await {
+                    Ok(Ok(connection)) => {
+                        let listener::Connection {
+                            reader,
+                            writer,
+                            history,
+                            linger,
+                        } = connection;
+                        let connection =
+                            process(hub.clone(), dialect, reader, writer, history, addr, &stop);
+                        if let Err(e) = connection.await {
+                            tracing::info!(error = %e, "connection failed");
+                        }
+                        hub.disconnect(addr.ip());
+                        linger
+                            .wait(Duration::from_secs(hub.config().shutdown_timeout))
+                            .await;
+                    }
+                    Ok(Err(e)) => {
+                        tracing::info!(error = %e, "couldn't set up the connection");
+                        hub.disconnect(addr.ip());
+                    }
+                    Err(_) => {
+                        tracing::info!("couldn't set up the connection in time");
+                        hub.disconnect(addr.ip());
+                    }
+                }
+                drop(closed);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Read the configuration again whenever the process gets SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(hub: Arc<Hub>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::warn!(error = %e, "can't reload the configuration on SIGHUP");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = hub.reload() {
+            tracing::error!(error = %e, "invalid configuration, keeping the old one");
+        }
+    }
+}
+
+/// Save the history whenever shared tags change, until `stop`. Unlike
+/// messages, of which only the recent ones are kept anyway, tags are meant
+/// to last, so they shouldn't be lost if the server crashes.
+async fn save_tags(hub: Arc<Hub>, stop: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = hub.edited_tags() => {}
+            _ = stop.cancelled() => return,
+        }
+        if let Err(e) = history::save(&hub.config().data_dir, &hub.snapshot().await) {
+            tracing::error!(error = %e, "couldn't save the shared tags");
+        }
+    }
+}
+
+/// Periodically log the state of the outbound queues.
+async fn log_stats(hub: Arc<Hub>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let stats = hub.stats();
+        tracing::info!(
+            peers = stats.peers,
+            rooms = stats.rooms,
+            queued = stats.queued,
+            longest_queue = stats.longest_queue,
+            dropped = stats.dropped,
+            "stats"
+        );
+    }
+}
+
+/// Encode a frame once, so that the same buffer can be queued for any number
+/// of peers.
+fn encode_line(frame: &ServerFrame) -> Bytes {
+    let mut line = protocol::encode(frame);
+    line.push('\n');
+    line.into()
+}
+
+/// How a client talks to the server.
+enum Mode {
+    /// JSON frames, see `protocol`.
+    Frames,
+    /// Plain text, see `text`. `room` is where typed lines go.
+    Text {
+        room: String,
+    },
+    Irc(irc::Session),
+}
+
+impl Mode {
+    /// An encoded frame the way this client gets it, `None` if it doesn't
+    /// get such frames.
+    fn render<'a>(&mut self, frame: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let line = match self {
+            Mode::Frames => return Some(Cow::Borrowed(frame)),
+            Mode::Text { .. } => text::render_line(frame),
+            Mode::Irc(session) => irc::render_line(frame, session),
+        };
+        line.map(|line| Cow::Owned(line.into_bytes()))
+    }
+}
+
+/// What a line from a client asks for.
+enum Input {
+    Frame(ClientFrame),
+    /// Stop receiving messages from a room.
+    Leave(String),
+    /// Lines to write back to the client as they are.
+    Reply(String),
+    /// Close the connection.
+    Quit,
+}
+
+/// The state for each connected client.
+struct Peer {
+    addr: PeerAddr,
+    username: String,
+    mode: Mode,
+
+    /// Write half of the socket. Messages from other peers are already
+    /// encoded, so they are written as they are.
+    writer: Writer,
+
+    /// Rate limit of this connection.
+    guard: FloodGuard,
+
+    /// Rate limit of the user, shared with their other connections.
+    account: Arc<Mutex<TokenBucket>>,
+
+    /// Messages from other peers waiting to be written to the socket.
+    outbox: Arc<Outbox>,
+
+    /// Where the history replayed on joining rooms is queued. The same as
+    /// `outbox`, unless the connection has a separate stream for it.
+    history: Arc<Outbox>,
+
+    /// Stops writing the history to its stream once the peer is gone.
+    _history_writer: Option<DropGuard>,
+
+    /// Cancelled when an operator kicks the peer.
+    kicked: CancellationToken,
+
+    metrics: Arc<Metrics>,
+
+    /// Rooms this peer has joined.
+    rooms: HashMap<String, Room>,
+}
+
+impl Peer {
+    /// Create a new instance of `Peer` and register it in the hub.
+    fn new(
+        hub: &Hub,
+        addr: PeerAddr,
+        username: String,
+        mode: Mode,
+        writer: Writer,
+        history_writer: Option<Writer>,
+        limits: &Limits,
+    ) -> Peer {
+        // Create a queue for this peer
+        let outbox = Arc::new(Outbox::new(limits.outbound_queue, limits.slow_consumer));
+
+        // The history gets its own queue if it has its own stream.
+        let (history, history_guard) = match history_writer {
+            Some(writer) => {
+                let capacity = limits.outbound_queue.max(limits.history);
+                let history = Arc::new(Outbox::new(capacity, SlowConsumerPolicy::Resync));
+                let done = CancellationToken::new();
+                tokio::spawn(
+                    write_history(history.clone(), writer, hub.metrics.clone(), done.clone())
+                        .in_current_span(),
+                );
+                (history, Some(done.drop_guard()))
+            }
+            None => (outbox.clone(), None),
+        };
+
+        // Add an entry for this `Peer` in the hub.
+        let kicked = CancellationToken::new();
+        hub.add_peer(addr, username.clone(), outbox.clone(), kicked.clone());
+
+        Peer {
+            addr,
+            account: hub.account(&username, limits),
+            username,
+            mode,
+            writer,
+            guard: FloodGuard::new(limits),
+            outbox,
+            history,
+            _history_writer: history_guard,
+            kicked,
+            metrics: hub.metrics.clone(),
+            rooms: HashMap::new(),
+        }
+    }
+
+    /// Write a frame meant only for this peer, skipping the outbox.
+    async fn send(&mut self, frame: &ServerFrame) -> io::Result<()> {
+        self.write(&encode_line(frame)).await
+    }
+
+    /// Write an encoded frame to the socket, translated for text and IRC
+    /// clients.
+    async fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(line) = self.mode.render(frame) {
+            self.writer.write_all(&line).await?;
+            self.metrics.frame_sent();
+        }
+        Ok(())
+    }
+
+    async fn notice(&mut self, text: String) -> io::Result<()> {
+        self.send(&ServerFrame::Notice { text }).await
+    }
+
+    /// Join a room, unless already in it. With `replay_after`, the recent
+    /// messages with greater ids are sent first.
+    async fn join(&mut self, hub: &Hub, name: &str, replay_after: Option<u64>) -> &Room {
+        // A closed room was deleted by an operator, writing to it creates a
+        // new one.
+        if self.rooms.get(name).map_or(true, Room::is_closed) {
+            let room = hub.room(name);
+            let replay = replay_after.map(|after| Replay {
+                after,
+                outbox: self.history.clone(),
+            });
+            room.join(self.addr, self.outbox.clone(), replay).await;
+            self.rooms.insert(name.to_string(), room);
+        }
+        &self.rooms[name]
+    }
+
+    async fn leave(&mut self, name: &str) {
+        if let Some(room) = self.rooms.remove(name) {
+            room.leave(self.addr).await;
+        }
+    }
+
+    async fn leave_all(&mut self) {
+        for (_, room) in self.rooms.drain() {
+            room.leave(self.addr).await;
+        }
+    }
+
+    /// Write what is still queued, giving up after `deadline`.
+    async fn flush(&mut self, deadline: Duration) -> io::Result<()> {
+        let flush = async {
+            while let Some(Next::Send(msg)) = self.outbox.try_pop() {
+                self.write(&msg).await?;
+            }
+            Ok(())
+        };
+        match tokio::time::timeout(deadline, flush).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!("couldn't flush the queue in time");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write the history queued for a peer to its own stream until `done` is
+/// cancelled.
+async fn write_history(
+    history: Arc<Outbox>,
+    mut writer: Writer,
+    metrics: Arc<Metrics>,
+    done: CancellationToken,
+) -> io::Result<()> {
+    loop {
+        let message = tokio::select! {
+            next = history.pop() => match next {
+                Next::Send(message) => message,
+                Next::Missed(count) => encode_line(&ServerFrame::Notice {
+                    text: format!("You missed {} messages, resync.", count),
+                }),
+                Next::Overflowed => return Ok(()),
+            },
+            _ = done.cancelled() => return writer.shutdown().await,
+        };
+        writer.write_all(&message).await?;
+        metrics.frame_sent();
+    }
+}
+
+/// Process an individual chat client
+async fn process(
+    hub: Arc<Hub>,
+    dialect: Dialect,
+    reader: Reader,
+    mut writer: Writer,
+    history: Option<Writer>,
+    addr: PeerAddr,
+    stop: &CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = hub.config();
+    let limits = &config.limits;
+    let mut lines = FramedRead::new(
+        reader,
+        LinesCodec::new_with_max_length(limits.max_line_length),
+    );
+
+    // The heartbeat starts after logging in, so until then a connection which
+    // doesn't say anything would hold its slot forever.
+    let login = Duration::from_secs(config.heartbeat.timeout);
+    let (username, mut mode) = match dialect {
+        Dialect::Irc => {
+            let register =
+                tokio::time::timeout(login, irc::register(&hub, &mut lines, &mut writer));
+            let registered = tokio::select! {
+                registered = register => match registered {
+                    Ok(registered) => registered?,
+                    Err(_) => {
+                        tracing::info!("didn't register in time");
+                        return Ok(());
+                    }
+                },
+                _ = stop.cancelled() => return Ok(()),
+            };
+            match registered {
+                Some(session) => (session.nick.clone(), Mode::Irc(session)),
+                None => return Ok(()),
+            }
+        }
+        Dialect::TagChat => {
+            // Read the first line from the `LineCodec` stream to get the username.
+            let first = tokio::select! {
+                first = tokio::time::timeout(login, lines.next()) => match first {
+                    Ok(first) => first,
+                    Err(_) => {
+                        tracing::info!("didn't log in in time");
+                        return Ok(());
+                    }
+                },
+                _ = stop.cancelled() => return Ok(()),
+            };
+            match first {
+                // Someone typing, e.g. with telnet, starts with their name.
+                Some(Ok(line)) if text::is_text(&line) => {
+                    let name = line.trim();
+                    if name.is_empty() {
+                        writer
+                            .write_all(text::lines("* Type your name first.").as_bytes())
+                            .await?;
+                        return Ok(());
+                    }
+                    let room = text::DEFAULT_ROOM.to_string();
+                    (name.to_string(), Mode::Text { room })
+                }
+                Some(Ok(line)) => match protocol::decode(&line) {
+                    Ok(ClientFrame::Login { name }) => (name, Mode::Frames),
+                    Ok(ClientFrame::Admin { token, command }) => {
+                        return admin::serve(&hub, stop, addr, token, command, &mut lines, writer)
+                            .await;
+                    }
+                    _ => {
+                        tracing::error!(%line, "expected a login frame");
+                        hub.metrics.auth_failure();
+                        return Ok(());
+                    }
+                },
+                // We didn't get a line so we return early here.
+                _ => {
+                    tracing::error!("disconnected before logging in");
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    tracing::Span::current().record("user", username.as_str());
+    if !valid_name(&username) {
+        tracing::warn!("refused a name with control characters");
+        let notice = ServerFrame::Notice {
+            text: "Names can't contain control characters.".into(),
+        };
+        if let Some(line) = mode.render(&encode_line(&notice)) {
+            writer.write_all(&line).await?;
+        }
+        return Ok(());
+    }
+    if hub.is_banned(&username) {
+        tracing::warn!("refused login of a banned user");
+        hub.metrics.auth_failure();
+        let notice = ServerFrame::Notice {
+            text: "You are banned from this server.".into(),
+        };
+        if let Some(line) = mode.render(&encode_line(&notice)) {
+            writer.write_all(&line).await?;
+        }
+        return Ok(());
+    }
+
+    // Text and IRC clients see the history among the other messages.
+    let history = match mode {
+        Mode::Frames => history,
+        Mode::Text { .. } | Mode::Irc(_) => None,
+    };
+
+    // Register our peer in the hub.
+    let mut peer = Peer::new(&hub, addr, username, mode, writer, history, limits);
+
+    let welcome = ServerFrame::Welcome {
+        heartbeat_interval: config.heartbeat.interval,
+        heartbeat_timeout: config.heartbeat.timeout,
+    };
+    peer.send(&welcome).await?;
+    if let Some(text) = hub.config().motd.clone() {
+        peer.send(&ServerFrame::Motd { text }).await?;
+    }
+
+    // A client has connected, let's let everyone know.
+    let msg = format!("{} has joined the chat", peer.username);
+    tracing::info!("logged in");
+    hub.broadcast(Some(addr), encode_line(&ServerFrame::Notice { text: msg }));
+
+    // Text clients can't ask to join a room before they know the commands.
+    if let Mode::Text { room } = &peer.mode {
+        let room = room.clone();
+        let welcome = format!(
+            "Welcome, {}! You are in {}, type /help for the commands.",
+            peer.username, room
+        );
+        peer.notice(welcome).await?;
+        peer.join(&hub, &room, Some(0)).await;
+    }
+
+    let result = serve(&hub, &mut peer, &mut lines, &config, stop).await;
+
+    // If this section is reached it means that the client was disconnected!
+    // Let's let everyone still connected know about it.
+    peer.leave_all().await;
+    hub.remove_peer(&addr);
+
+    let msg = format!("{} has left the chat", peer.username);
+    tracing::info!("logged out");
+    // Everyone is leaving when the server shuts down.
+    if !stop.is_cancelled() {
+        hub.broadcast(Some(addr), encode_line(&ServerFrame::Notice { text: msg }));
+    }
+
+    result
+}
+
+/// Whether a user or a room can have this name. Control characters would let
+/// it break the lines text and IRC clients get.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(char::is_control)
+}
+
+/// Decode a line from the client. Returns `None` if it isn't a valid frame.
+async fn decode(hub: &Hub, peer: &mut Peer, line: &str) -> Option<Vec<Input>> {
+    match &mut peer.mode {
+        Mode::Frames => protocol::decode(line)
+            .ok()
+            .map(|frame| vec![Input::Frame(frame)]),
+        Mode::Text { room } => Some(text::parse(hub, line, room).await),
+        Mode::Irc(session) => Some(irc::parse(hub, line, session).await),
+    }
+}
+
+/// Do what the client asked for. Returns `false` once the connection should
+/// be closed.
+async fn handle(
+    hub: &Hub,
+    peer: &mut Peer,
+    input: Input,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    match input {
+        Input::Reply(lines) => peer.writer.write_all(lines.as_bytes()).await?,
+        Input::Quit => return Ok(false),
+        Input::Leave(room) => peer.leave(&room).await,
+        Input::Frame(ClientFrame::Ping) => peer.send(&ServerFrame::Pong).await?,
+        Input::Frame(ClientFrame::Pong) => {}
+        Input::Frame(
+            ClientFrame::Join { ref room, .. }
+            | ClientFrame::Message { ref room, .. }
+            | ClientFrame::EditTags { ref room, .. },
+        ) if !valid_name(room) => {
+            peer.notice("Room names can't be empty or contain control characters.".into())
+                .await?;
+        }
+        Input::Frame(ClientFrame::Join { room, since }) => {
+            peer.join(hub, &room, Some(since.unwrap_or(0))).await;
+        }
+        // A message was received from the current user, we should broadcast
+        // this message to the users in the room, or to its recipient.
+        // It has passed `release` already.
+        Input::Frame(
+            frame @ (ClientFrame::Message { .. }
+            | ClientFrame::Direct { .. }
+            | ClientFrame::EditTags { .. }),
+        ) => {
+            peer.guard.take(&mut peer.account.lock().unwrap());
+            hub.metrics.message_received();
+            let received = Instant::now();
+
+            let username = peer.username.clone();
+            match frame {
+                ClientFrame::Message { room, content } => {
+                    tracing::debug!(%room, bytes = content.len(), "message received");
+                    let room = peer.join(hub, &room, None).await;
+                    room.publish(username, content, received).await;
+                }
+                ClientFrame::Direct { to, content } => {
+                    tracing::debug!(%to, bytes = content.len(), "direct message received");
+                    let frame = ServerFrame::Direct {
+                        sender: username,
+                        content,
+                    };
+                    if hub.direct(&to, encode_line(&frame)) == 0 {
+                        peer.notice(format!("{} is not connected.", to)).await?;
+                    }
+                }
+                ClientFrame::EditTags { room, edit } => {
+                    tracing::debug!(%room, ?edit, "tags edited");
+                    let room = peer.join(hub, &room, None).await;
+                    match room.edit_tags(username, edit).await {
+                        Ok(_) => hub.tags_edited(),
+                        Err(reason) => peer.notice(reason).await?,
+                    }
+                }
+                _ => unreachable!("only messages and edits are matched above"),
+            }
+        }
+        Input::Frame(frame) => tracing::warn!(?frame, "unexpected frame"),
+    }
+    Ok(true)
+}
+
+/// Whether an input counts against the rate limits.
+fn is_limited(input: &Input) -> bool {
+    matches!(
+        input,
+        Input::Frame(
+            ClientFrame::Message { .. } | ClientFrame::Direct { .. } | ClientFrame::EditTags { .. }
+        )
+    )
+}
+
+/// Handle the inputs waiting in `pending`, in order. A message over the rate
+/// limit stays at the front of the queue, with `throttled` set to when it can
+/// go. `waited` means that the first one was throttled and can go now.
+/// Returns `false` once the connection should be closed.
+async fn release(
+    hub: &Hub,
+    peer: &mut Peer,
+    pending: &mut VecDeque<Input>,
+    throttled: &mut Option<tokio::time::Instant>,
+    mut waited: bool,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    while let Some(input) = pending.pop_front() {
+        if is_limited(&input) && !std::mem::take(&mut waited) {
+            let verdict = peer
+                .guard
+                .check(&hub.config().limits, &mut peer.account.lock().unwrap());
+            hub.metrics.rate_limited(&verdict);
+            match verdict {
+                Verdict::Allow => {}
+                Verdict::Throttle(wait) => {
+                    tracing::info!(?wait, "throttling");
+                    pending.push_front(input);
+                    *throttled = Some(tokio::time::Instant::now() + wait);
+                    return Ok(true);
+                }
+                Verdict::Warn => {
+                    tracing::warn!(reason = "flooding", "dropped a message");
+                    peer.notice("You are sending messages too fast, slow down.".into())
+                        .await?;
+                    continue;
+                }
+                Verdict::Disconnect => {
+                    tracing::warn!(reason = "flooding", "disconnecting");
+                    peer.notice("Disconnected for sending messages too fast.".into())
+                        .await?;
+                    return Ok(false);
+                }
+            }
+        }
+        if !handle(hub, peer, input).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Process incoming messages until our stream is exhausted by a disconnect.
+async fn serve(
+    hub: &Hub,
+    peer: &mut Peer,
+    lines: &mut FramedRead<Reader, LinesCodec>,
+    config: &Config,
+    stop: &CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let limits = &config.limits;
+    let Heartbeat {
+        interval,
+        timeout,
+        text_timeout,
+    } = config.heartbeat;
+    // Text clients can't answer pings, they just get more time.
+    let text = matches!(peer.mode, Mode::Text { .. });
+    let timeout = if text { text_timeout } else { timeout };
+    let (interval, timeout) = (Duration::from_secs(interval), Duration::from_secs(timeout));
+    let mut heartbeat = tokio::time::interval(interval);
+    // When the client sent anything for the last time.
+    let mut last_seen = Instant::now();
+
+    // `FramedRead` yields a single `None` after a line fails to decode, but
+    // the codec skips the rest of such a line and reading can go on
+    // afterwards.
+    let mut resumed_after_error = false;
+
+    // Inputs not handled yet, because the first one is throttled until the
+    // deadline. Nothing more is read in the meantime, but the peer keeps
+    // getting messages and pings.
+    let mut pending = VecDeque::new();
+    let mut throttled = None;
+
+    loop {
+        tokio::select! {
+            // The server is shutting down. The `Shutdown` frame is already
+            // queued.
+            _ = stop.cancelled() => {
+                peer.flush(Duration::from_secs(config.shutdown_timeout)).await?;
+                return Ok(());
+            }
+            _ = peer.kicked.cancelled() => {
+                tracing::warn!(reason = "kicked", "disconnecting");
+                peer.notice("You have been disconnected by an operator.".into()).await?;
+                return Ok(());
+            }
+            _ = heartbeat.tick() => {
+                let idle = last_seen.elapsed();
+                if idle >= timeout {
+                    tracing::warn!(reason = "timed out", ?idle, "disconnecting");
+                    return Ok(());
+                }
+                if idle >= interval && !text {
+                    peer.send(&ServerFrame::Ping).await?;
+                }
+            }
+            // A message was received from a peer. Send it to the current user.
+            next = peer.outbox.pop() => match next {
+                Next::Send(msg) => peer.write(&msg).await?,
+                Next::Missed(count) => {
+                    tracing::info!(count, "missed messages");
+                    peer.notice(format!("You missed {} messages, resync.", count)).await?;
+                }
+                Next::Overflowed => {
+                    tracing::warn!(reason = "too slow", "disconnecting");
+                    return Ok(());
+                }
+            },
+            // The throttled message can go now.
+            _ = tokio::time::sleep_until(throttled.unwrap_or_else(tokio::time::Instant::now)),
+                if throttled.is_some() =>
+            {
+                throttled = None;
+                if !release(hub, peer, &mut pending, &mut throttled, true).await? {
+                    return Ok(());
+                }
+            }
+            result = lines.next(), if throttled.is_none() => match result {
+                Some(Ok(line)) => match decode(hub, peer, &line).await {
+                    Some(inputs) => {
+                        last_seen = Instant::now();
+                        pending.extend(inputs);
+                        if !release(hub, peer, &mut pending, &mut throttled, false).await? {
+                            return Ok(());
+                        }
+                    }
+                    None => tracing::warn!(%line, "unexpected frame"),
+                },
+                Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                    tracing::warn!(reason = "too long", "dropped a message");
+                    peer.notice(format!("Messages can be at most {} bytes long.", limits.max_line_length)).await?;
+                    resumed_after_error = true;
+                }
+                // An error occurred.
+                Some(Err(e)) => {
+                    tracing::error!(error = %e, "failed to read a message");
+                }
+                None if resumed_after_error => resumed_after_error = false,
+                // The stream has been exhausted.
+                None => return Ok(()),
+            },
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Bounded queues of messages waiting to be written to peers.
+//!
+//! A peer which reads slower than the others write can't make the server
+//! buffer without limit. Once its queue is full, the configured
+//! `SlowConsumerPolicy` decides what happens.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Disconnect the peer.
+    Disconnect,
+    /// Drop new messages and tell the peer how many it missed once it catches
+    /// up.
+    Resync,
+}
+
+/// What a peer should do next.
+#[derive(Debug, PartialEq)]
+pub enum Next {
+    Send(Bytes),
+    /// The given number of messages were dropped, the peer should be told to
+    /// resync.
+    Missed(u64),
+    /// The queue overflowed and the peer should be disconnected.
+    Overflowed,
+}
+
+struct Queue {
+    messages: VecDeque<Bytes>,
+    missed: u64,
+    overflowed: bool,
+}
+
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    notify: Notify,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    /// Total number of messages dropped from this queue.
+    dropped: AtomicU64,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        Outbox {
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                missed: 0,
+                overflowed: false,
+            }),
+            notify: Notify::new(),
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a message, applying the slow consumer policy if the queue is
+    /// full.
+    pub fn push(&self, message: Bytes) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.messages.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    queue.messages.pop_front();
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    queue.overflowed = true;
+                    self.notify.notify_one();
+                    return;
+                }
+                SlowConsumerPolicy::Resync => {
+                    queue.missed += 1;
+                    return;
+                }
+            }
+        }
+        queue.messages.push_back(message);
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next thing to do. Cancel safe, so it can be used in
+    /// `tokio::select!`.
+    pub async fn pop(&self) -> Next {
+        loop {
+            let notified = self.notify.notified();
+            if let Some(next) = self.try_pop() {
+                return next;
+            }
+            notified.await;
+        }
+    }
+
+    /// The next thing to do, if there is any.
+    pub fn try_pop(&self) -> Option<Next> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.overflowed {
+            return Some(Next::Overflowed);
+        }
+        if let Some(message) = queue.messages.pop_front() {
+            return Some(Next::Send(message));
+        }
+        if queue.missed > 0 {
+            return Some(Next::Missed(std::mem::take(&mut queue.missed)));
+        }
+        None
+    }
+
+    /// Number of queued messages.
+    pub fn depth(&self) -> usize {
+        self.queue.lock().unwrap().messages.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A full queue of two, with one more message pushed.
+    fn overfilled(policy: SlowConsumerPolicy) -> Outbox {
+        let outbox = Outbox::new(2, policy);
+        for message in ["1", "2", "3"] {
+            outbox.push(Bytes::from(message));
+        }
+        outbox
+    }
+
+    fn sent(message: &str) -> Option<Next> {
+        Some(Next::Send(Bytes::from(message.to_string())))
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_messages() {
+        let outbox = overfilled(SlowConsumerPolicy::DropOldest);
+        assert_eq!((outbox.depth(), outbox.dropped()), (2, 1));
+        assert_eq!(outbox.try_pop(), sent("2"));
+        assert_eq!(outbox.try_pop(), sent("3"));
+        assert_eq!(outbox.try_pop(), None);
+    }
+
+    #[test]
+    fn disconnect_comes_before_the_queued_messages() {
+        let outbox = overfilled(SlowConsumerPolicy::Disconnect);
+        assert_eq!((outbox.depth(), outbox.dropped()), (2, 1));
+        assert_eq!(outbox.try_pop(), Some(Next::Overflowed));
+        assert_eq!(outbox.try_pop(), Some(Next::Overflowed));
+    }
+
+    #[test]
+    fn resync_counts_the_missed_messages_after_the_queued_ones() {
+        let outbox = overfilled(SlowConsumerPolicy::Resync);
+        outbox.push(Bytes::from("4"));
+        assert_eq!((outbox.depth(), outbox.dropped()), (2, 2));
+        assert_eq!(outbox.try_pop(), sent("1"));
+        assert_eq!(outbox.try_pop(), sent("2"));
+        assert_eq!(outbox.try_pop(), Some(Next::Missed(2)));
+        assert_eq!(outbox.try_pop(), None);
+
+        // Once it caught up, messages are queued again.
+        outbox.push(Bytes::from("5"));
+        assert_eq!(outbox.try_pop(), sent("5"));
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_a_message() {
+        let outbox = std::sync::Arc::new(Outbox::new(2, SlowConsumerPolicy::DropOldest));
+        let waiting = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.pop().await }
+        });
+        tokio::task::yield_now().await;
+        outbox.push(Bytes::from("1"));
+        assert_eq!(waiting.await.unwrap(), Next::Send(Bytes::from("1")));
+    }
+}
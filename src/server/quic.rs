@@ -0,0 +1,54 @@
+//! QUIC transport, enabled with the `quic` feature.
+//!
+//! A client opens one bidirectional stream and talks on it exactly as it
+//! would over TCP. The server opens a unidirectional stream for the history
+//! replayed when the client joins rooms, so that a large backfill doesn't hold
+//! up live messages.
+//!
+//! There is no stream for attachments, because the protocol has no
+//! attachments. Adding them is left for when the protocol gets them, as a
+//! third stream.
+//!
+//! The certificate comes from the `[tls]` section. Without one, the server
+//! generates a self-signed certificate for `localhost` and saves it in
+//! `data_dir`, so that a local client can be told to trust it.
+
+use std::io;
+use std::path::Path;
+
+use quinn::rustls::pki_types::PrivatePkcs8KeyDer;
+
+use super::config::{Quic, Tls};
+use super::listener::{Connection, Linger};
+use super::tls::{self, invalid};
+
+/// Where the self-signed certificate is saved in `data_dir`.
+const CERT_FILE: &str = "quic-cert.pem";
+
+pub fn bind(quic: &Quic, tls: Option<&Tls>, data_dir: &Path) -> io::Result<quinn::Endpoint> {
+    let (chain, key) = match tls {
+        Some(settings) => tls::load(settings)?,
+        None => {
+            let generated =
+                rcgen::generate_simple_self_signed(vec!["localhost".into()]).map_err(invalid)?;
+            std::fs::write(data_dir.join(CERT_FILE), generated.cert.pem())?;
+            let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+            (vec![generated.cert.der().clone()], key.into())
+        }
+    };
+    let config = quinn::ServerConfig::with_single_cert(chain, key).map_err(invalid)?;
+    quinn::Endpoint::server(config, quic.listen)
+}
+
+/// Finish the handshake and wait for the client to open its stream.
+pub async fn establish(incoming: quinn::Incoming) -> io::Result<Connection> {
+    let connection = incoming.await?;
+    let (writer, reader) = connection.accept_bi().await?;
+    let history = connection.open_uni().await?;
+    Ok(Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        history: Some(Box::new(history)),
+        linger: Linger(Some(connection)),
+    })
+}
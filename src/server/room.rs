@@ -0,0 +1,303 @@
+//! Chat rooms.
+//!
+//! Every room is an actor: a task which owns the list of the room's members and
+//! fans messages out to them. Publishing a message only talks to the room's
+//! task, so rooms don't get in each other's way and nothing shared by the
+//! whole server is locked while messages are delivered.
+//!
+//! The task also numbers the messages and remembers the most recent ones, so
+//! that clients coming back can catch up on what they missed, and keeps the
+//! room's shared tags, see `shared_tags`.
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
+
+use super::encode_line;
+use super::history::RoomHistory;
+use super::listener::PeerAddr;
+use super::metrics::Metrics;
+use super::outbox::Outbox;
+use crate::protocol::{self, ServerFrame, TagEdit};
+
+/// How many commands can wait for a room's task before senders have to wait.
+const MAILBOX_SIZE: usize = 1024;
+
+enum Command {
+    Join {
+        addr: PeerAddr,
+        outbox: Arc<Outbox>,
+        replay: Option<Replay>,
+    },
+    Leave(PeerAddr),
+    Publish {
+        sender: String,
+        content: String,
+        /// When the message was read from the sender.
+        received: Instant,
+    },
+    EditTags {
+        user: String,
+        edit: TagEdit,
+        done: oneshot::Sender<Result<ServerFrame, String>>,
+    },
+    Tags(oneshot::Sender<ServerFrame>),
+    Snapshot(oneshot::Sender<RoomHistory>),
+    Info(oneshot::Sender<RoomInfo>),
+    /// Tell the members the room is gone and stop, answering with the id the
+    /// next message would have got.
+    Close(oneshot::Sender<u64>),
+}
+
+pub struct RoomInfo {
+    pub members: Vec<PeerAddr>,
+    /// Number of recent messages kept.
+    pub messages: usize,
+}
+
+/// Recent messages to send to a peer joining a room.
+pub struct Replay {
+    /// Only messages with greater ids are sent.
+    pub after: u64,
+    /// Where to queue them. It can be a separate queue from the live messages.
+    pub outbox: Arc<Outbox>,
+}
+
+/// Handle to a room's task.
+#[derive(Clone)]
+pub struct Room {
+    tx: mpsc::Sender<Command>,
+}
+
+impl Room {
+    /// Start the task of a room, keeping up to `capacity` recent messages.
+    pub fn spawn(
+        name: String,
+        history: RoomHistory,
+        capacity: usize,
+        metrics: Arc<Metrics>,
+    ) -> Room {
+        let (tx, rx) = mpsc::channel(MAILBOX_SIZE);
+        // Rooms outlive the connection which created them.
+        let span = tracing::info_span!(parent: None, "room", room = %name);
+        tokio::spawn(run(name, history, capacity, metrics, rx).instrument(span));
+        Room { tx }
+    }
+
+    /// Start delivering the room's messages to `outbox`, after queueing the
+    /// recent ones asked for with `replay` and the room's shared tags.
+    pub async fn join(&self, addr: PeerAddr, outbox: Arc<Outbox>, replay: Option<Replay>) {
+        let command = Command::Join {
+            addr,
+            outbox,
+            replay,
+        };
+        let _ = self.tx.send(command).await;
+    }
+
+    pub async fn leave(&self, addr: PeerAddr) {
+        let _ = self.tx.send(Command::Leave(addr)).await;
+    }
+
+    /// Number a message and queue it for every member of the room, the sender
+    /// included.
+    pub async fn publish(&self, sender: String, content: String, received: Instant) {
+        let command = Command::Publish {
+            sender,
+            content,
+            received,
+        };
+        let _ = self.tx.send(command).await;
+    }
+
+    /// Change the room's shared tags and tell its members about it. Returns
+    /// the frame they got, or why `user` can't make the change.
+    pub async fn edit_tags(&self, user: String, edit: TagEdit) -> Result<ServerFrame, String> {
+        let (done, rx) = oneshot::channel();
+        let _ = self.tx.send(Command::EditTags { user, edit, done }).await;
+        rx.await
+            .unwrap_or_else(|_| Err("The room was deleted.".to_string()))
+    }
+
+    /// The room's shared tags, as a `RoomTags` frame.
+    pub async fn tags(&self) -> Option<ServerFrame> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Command::Tags(tx)).await;
+        rx.await.ok()
+    }
+
+    /// The room's recent messages and shared tags, to be saved.
+    pub async fn snapshot(&self) -> RoomHistory {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Command::Snapshot(tx)).await;
+        rx.await.unwrap_or_default()
+    }
+
+    pub async fn info(&self) -> Option<RoomInfo> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Command::Info(tx)).await;
+        rx.await.ok()
+    }
+
+    /// Stop the room. Returns the id its next message would have got, or
+    /// `None` if it was closed already.
+    pub async fn close(&self) -> Option<u64> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Command::Close(tx)).await;
+        rx.await.ok()
+    }
+
+    /// Whether the room's task has stopped, after `close`.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+async fn run(
+    name: String,
+    history: RoomHistory,
+    capacity: usize,
+    metrics: Arc<Metrics>,
+    mut rx: mpsc::Receiver<Command>,
+) {
+    let mut members: HashMap<PeerAddr, Arc<Outbox>> = HashMap::new();
+    let mut next_id = history.next_id.max(1);
+    let mut tags = history.tags;
+    // Recent messages with their ids, already encoded.
+    let mut recent: VecDeque<(u64, Bytes)> = history
+        .messages
+        .iter()
+        .filter_map(|frame| match frame {
+            ServerFrame::Message { id, .. } => Some((*id, encode_line(frame))),
+            _ => None,
+        })
+        .collect();
+    while recent.len() > capacity {
+        recent.pop_front();
+    }
+    tracing::debug!(messages = recent.len(), "room created");
+
+    while let Some(command) = rx.recv().await {
+        match command {
+            Command::Join {
+                addr,
+                outbox,
+                replay,
+            } => {
+                // The tags come after the messages they are on.
+                let tags = encode_line(&tags.frame(&name));
+                match replay {
+                    Some(replay) => {
+                        for (_, message) in recent.iter().filter(|(id, _)| *id > replay.after) {
+                            replay.outbox.push(message.clone());
+                        }
+                        replay.outbox.push(tags);
+                    }
+                    None => outbox.push(tags),
+                }
+                members.insert(addr, outbox);
+                tracing::debug!(peer = %addr, members = members.len(), "joined");
+            }
+            Command::Leave(addr) => {
+                if members.remove(&addr).is_some() {
+                    tracing::debug!(peer = %addr, members = members.len(), "left");
+                }
+            }
+            Command::Publish {
+                sender,
+                content,
+                received,
+            } => {
+                let id = next_id;
+                next_id += 1;
+                let sent = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .ok()
+                    .map(|since| since.as_secs());
+                let message = encode_line(&ServerFrame::Message {
+                    id,
+                    sender,
+                    room: name.clone(),
+                    content,
+                    sent,
+                });
+                for outbox in members.values() {
+                    // Only the reference count of `message` changes here, the
+                    // contents are shared by all the queues.
+                    outbox.push(message.clone());
+                }
+                metrics.broadcast_latency(received.elapsed());
+                tracing::debug!(
+                    id,
+                    bytes = message.len(),
+                    members = members.len(),
+                    "message published"
+                );
+                if capacity > 0 {
+                    if recent.len() >= capacity {
+                        recent.pop_front();
+                    }
+                    recent.push_back((id, message));
+                }
+            }
+            Command::EditTags {
+                user,
+                mut edit,
+                done,
+            } => {
+                // Messages which haven't been sent yet can't have tags.
+                if let TagEdit::Assign { messages, .. } = &mut edit {
+                    messages.retain(|id| *id < next_id);
+                }
+                let result = tags.edit(&user, &name, edit).map(|frame| {
+                    let line = encode_line(&frame);
+                    for outbox in members.values() {
+                        outbox.push(line.clone());
+                    }
+                    frame
+                });
+                if let Err(reason) = &result {
+                    tracing::debug!(%user, reason, "tags not changed");
+                }
+                let _ = done.send(result);
+            }
+            Command::Tags(tx) => {
+                let _ = tx.send(tags.frame(&name));
+            }
+            Command::Snapshot(tx) => {
+                let messages = recent
+                    .iter()
+                    .map(|(_, message)| {
+                        let line = std::str::from_utf8(message).expect("frames are UTF-8");
+                        protocol::decode(line).expect("history holds encoded frames")
+                    })
+                    .collect();
+                let _ = tx.send(RoomHistory {
+                    next_id,
+                    messages,
+                    tags: tags.clone(),
+                });
+            }
+            Command::Info(tx) => {
+                let _ = tx.send(RoomInfo {
+                    members: members.keys().copied().collect(),
+                    messages: recent.len(),
+                });
+            }
+            Command::Close(tx) => {
+                let notice = encode_line(&ServerFrame::Notice {
+                    text: format!("The room {} was deleted.", name),
+                });
+                for outbox in members.values() {
+                    outbox.push(notice.clone());
+                }
+                let _ = tx.send(next_id);
+                break;
+            }
+        }
+    }
+    tracing::debug!("room closed");
+}
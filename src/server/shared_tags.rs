@@ -0,0 +1,116 @@
+//! Tags shared by the members of a room.
+//!
+//! Every room has its own tags, which its task keeps together with the ids of
+//! the messages which have them. They are saved with the history of the rooms
+//! after every change. Anyone in the room can create tags and put them on
+//! messages, but only the user who created a tag can rename, recolor or
+//! delete it.
+//!
+//! That isn't a security boundary: users are only the names they log in
+//! with, which nobody checks, so anyone logging in with the owner's name can
+//! change the owner's tags. It keeps members from changing each other's tags
+//! by mistake.
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{ServerFrame, SharedTag, TagEdit, Tagged};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoomTags {
+    tags: Vec<SharedTag>,
+    tagged: Tagged,
+    /// Id of the last tag created. Ids aren't reused, even after deleting a
+    /// tag.
+    last_id: u64,
+}
+
+impl RoomTags {
+    /// Apply a change made by `user` to the tags of `room`. Returns the frame
+    /// which tells the room's members about it, or why it isn't allowed.
+    pub fn edit(&mut self, user: &str, room: &str, edit: TagEdit) -> Result<ServerFrame, String> {
+        let room = room.to_string();
+        match edit {
+            TagEdit::Create { name, color } => {
+                let name = checked_name(name)?;
+                self.last_id += 1;
+                let tag = SharedTag {
+                    id: self.last_id,
+                    name,
+                    color,
+                    owner: user.to_string(),
+                };
+                self.tags.push(tag.clone());
+                Ok(ServerFrame::TagChanged { room, tag })
+            }
+            TagEdit::Change { id, name, color } => {
+                let name = checked_name(name)?;
+                let tag = self.owned(user, id)?;
+                tag.name = name;
+                tag.color = color;
+                let tag = tag.clone();
+                Ok(ServerFrame::TagChanged { room, tag })
+            }
+            TagEdit::Delete { id } => {
+                self.owned(user, id)?;
+                self.tags.retain(|tag| tag.id != id);
+                self.tagged.remove(&id);
+                Ok(ServerFrame::TagDeleted { room, id })
+            }
+            TagEdit::Assign { id, messages, on } => {
+                self.get(id)?;
+                let tagged = self.tagged.entry(id).or_default();
+                for message in &messages {
+                    if on {
+                        tagged.insert(*message);
+                    } else {
+                        tagged.remove(message);
+                    }
+                }
+                if tagged.is_empty() {
+                    self.tagged.remove(&id);
+                }
+                Ok(ServerFrame::Tagged {
+                    room,
+                    id,
+                    messages,
+                    on,
+                })
+            }
+        }
+    }
+
+    /// All the tags of `room`, for a member who just joined it.
+    pub fn frame(&self, room: &str) -> ServerFrame {
+        ServerFrame::RoomTags {
+            room: room.to_string(),
+            tags: self.tags.clone(),
+            tagged: self.tagged.clone(),
+        }
+    }
+
+    fn get(&mut self, id: u64) -> Result<&mut SharedTag, String> {
+        self.tags
+            .iter_mut()
+            .find(|tag| tag.id == id)
+            .ok_or_else(|| "The tag doesn't exist anymore.".to_string())
+    }
+
+    /// A tag `user` is allowed to change.
+    fn owned(&mut self, user: &str, id: u64) -> Result<&mut SharedTag, String> {
+        let tag = self.get(id)?;
+        if tag.owner != user {
+            return Err(format!(
+                "Only {} can change the tag {}.",
+                tag.owner, tag.name
+            ));
+        }
+        Ok(tag)
+    }
+}
+
+fn checked_name(name: String) -> Result<String, String> {
+    match name.trim() {
+        "" => Err("Tags need a name.".to_string()),
+        name => Ok(name.to_string()),
+    }
+}
@@ -0,0 +1,26 @@
+//! Files in `data_dir`, holding state which has to survive restarts.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Read a JSON file written by `save`. A missing file means nothing has been
+/// saved yet.
+pub fn load<T: DeserializeOwned + Default>(data_dir: &Path, name: &str) -> io::Result<T> {
+    match fs::read_to_string(data_dir.join(name)) {
+        Ok(text) => Ok(serde_json::from_str(&text)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn save<T: Serialize>(data_dir: &Path, name: &str, value: &T) -> io::Result<()> {
+    // Write to a temporary file first, so that a crash halfway through doesn't
+    // destroy what was saved before.
+    let path = data_dir.join(name);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    fs::rename(tmp, path)
+}
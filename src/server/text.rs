@@ -0,0 +1,146 @@
+//! Plain text mode, for talking to the server with `telnet` or `nc`.
+//!
+//! A connection whose first line isn't a JSON object is in this mode, and the
+//! line is the user's name. Every following line is a message to the current
+//! room, unless it starts with `/`:
+//!
+//! ```text
+//! /join <room>   join a room and write to it from now on
+//! /msg <user> <text>
+//!                send a private message
+//! /rooms         list the rooms
+//! /who           list the connected users
+//! /help          list the commands
+//! /quit          disconnect
+//! ```
+//!
+//! Frames from the server are written as readable lines ending with `\r\n`,
+//! messages prefixed with their room. Text clients don't get pings, so they
+//! are disconnected only after `heartbeat.text_timeout` seconds of silence,
+//! much longer than the other clients.
+
+use std::fmt::Write;
+
+use super::hub::Hub;
+use super::Input;
+use crate::protocol::{self, ClientFrame, ServerFrame};
+
+/// Room text clients start in, the same one the GUI opens.
+pub const DEFAULT_ROOM: &str = "Wspólny";
+
+const HELP: &str = "Commands:
+/join <room>   join a room and write to it from now on
+/msg <user> <text>
+               send a private message
+/rooms         list the rooms
+/who           list the connected users
+/help          show this help
+/quit          disconnect
+Anything else is sent to the current room.";
+
+/// Whether the first line of a connection starts the plain text mode.
+pub fn is_text(first: &str) -> bool {
+    !first.trim_start().starts_with('{')
+}
+
+/// Parse a line typed by a text client. `room` is the current room, which
+/// `/join` changes.
+pub async fn parse(hub: &Hub, line: &str, room: &mut String) -> Vec<Input> {
+    vec![parse_one(hub, line, room).await]
+}
+
+async fn parse_one(hub: &Hub, line: &str, room: &mut String) -> Input {
+    let line = line.trim_end();
+    if line.trim().is_empty() {
+        return reply("Type a message, or /help for the commands.");
+    }
+    let command = match line.strip_prefix('/') {
+        Some(command) => command,
+        None => {
+            return Input::Frame(ClientFrame::Message {
+                room: room.clone(),
+                content: line.to_string(),
+            })
+        }
+    };
+    let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+    let arg = arg.trim();
+    match name {
+        "join" if !arg.is_empty() => {
+            *room = arg.to_string();
+            Input::Frame(ClientFrame::Join {
+                room: room.clone(),
+                since: None,
+            })
+        }
+        "join" => reply("Usage: /join <room>"),
+        "msg" => match arg.split_once(' ') {
+            Some((to, content)) => Input::Frame(ClientFrame::Direct {
+                to: to.to_string(),
+                content: content.trim().to_string(),
+            }),
+            None => reply("Usage: /msg <user> <text>"),
+        },
+        "rooms" => {
+            let mut out = String::from("Rooms:");
+            for (name, handle) in hub.rooms() {
+                if let Some(info) = handle.info().await {
+                    let _ = write!(out, "\n{} ({} members)", name, info.members.len());
+                }
+            }
+            reply(&out)
+        }
+        "who" => {
+            let mut users: Vec<_> = hub.peers().into_iter().map(|(_, user)| user).collect();
+            users.dedup();
+            reply(&format!("Connected: {}", users.join(", ")))
+        }
+        "help" => reply(HELP),
+        "quit" => Input::Quit,
+        _ => reply(&format!("Unknown command /{}, see /help.", name)),
+    }
+}
+
+fn reply(text: &str) -> Input {
+    Input::Reply(lines(text))
+}
+
+/// Write a frame the way a text client shows it. Returns `None` for frames
+/// text clients don't see.
+pub fn render(frame: &ServerFrame) -> Option<String> {
+    let text = match frame {
+        ServerFrame::Message {
+            sender,
+            room,
+            content,
+            ..
+        } => format!("[{}] {}: {}", room, sender, content),
+        ServerFrame::Direct { sender, content } => format!("(private) {}: {}", sender, content),
+        ServerFrame::Notice { text } => format!("* {}", text),
+        ServerFrame::Motd { text } => format!("Message of the day: {}", text),
+        ServerFrame::Announcement { text } => format!("*** {}", text),
+        ServerFrame::Shutdown { reason } => format!("* Disconnecting: {}", reason),
+        ServerFrame::AdminReply { text, .. } => text.clone(),
+        ServerFrame::Ping
+        | ServerFrame::Pong
+        | ServerFrame::Welcome { .. }
+        | ServerFrame::RoomTags { .. }
+        | ServerFrame::TagChanged { .. }
+        | ServerFrame::TagDeleted { .. }
+        | ServerFrame::Tagged { .. } => return None,
+    };
+    Some(lines(&text))
+}
+
+/// End every line of `text` with "\r\n", as telnet expects.
+pub fn lines(text: &str) -> String {
+    let mut out = text.replace('\n', "\r\n");
+    out.push_str("\r\n");
+    out
+}
+
+/// `render` for a frame which is already encoded, as they are in the outbox.
+pub fn render_line(line: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    render(&protocol::decode(line.trim_end()).ok()?)
+}
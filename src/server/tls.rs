@@ -0,0 +1,38 @@
+//! TLS for the TCP listeners.
+//!
+//! With a `[tls]` section, every address from `bind` accepts only TLS
+//! connections, with the certificate chain and the private key from the PEM
+//! files it names. The Unix socket and the IRC listener stay in plain text.
+
+use std::io;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use super::config::Tls;
+
+pub fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Read the certificate chain and the private key.
+pub fn load(tls: &Tls) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let chain = CertificateDer::pem_file_iter(&tls.cert)
+        .map_err(invalid)?
+        .collect::<Result<_, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key).map_err(invalid)?;
+    Ok((chain, key))
+}
+
+pub fn acceptor(tls: &Tls) -> io::Result<TlsAcceptor> {
+    let (chain, key) = load(tls)?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(invalid)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
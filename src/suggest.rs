@@ -0,0 +1,226 @@
+//! Tag suggestions, learned from the messages the user already tagged.
+//!
+//! The model is a naive Bayes classifier over the words of messages, each
+//! word weighted by TF-IDF. Every tag is a class, and so are messages without
+//! tags, so that the model can tell that a message doesn't look like any tag.
+//! It's small and fast enough to be trained again from scratch whenever the
+//! user asks for it.
+
+use std::collections::HashMap;
+
+use crate::tags::{TagId, TagSet};
+
+/// Messages of one tag, or the untagged ones.
+#[derive(Debug, Default)]
+struct Class {
+    /// Number of messages.
+    messages: usize,
+    /// Summed weights of every word in the messages.
+    words: HashMap<String, f64>,
+    total: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Model {
+    tags: HashMap<TagId, Class>,
+    untagged: Class,
+    /// Inverse document frequency of every known word.
+    idf: HashMap<String, f64>,
+    messages: usize,
+}
+
+/// A tag the model thinks a message should have.
+#[derive(Debug, Clone, Copy)]
+pub struct Suggestion {
+    pub tag: TagId,
+    /// Probability of the tag, from 0 to 1.
+    pub confidence: f32,
+}
+
+/// Lowercase words of a text, without punctuation and single letters.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().nth(1).is_some())
+        .map(str::to_lowercase)
+}
+
+/// Words of a text with how many times they appear.
+pub fn counts(text: &str) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for word in words(text) {
+        *counts.entry(word).or_default() += 1;
+    }
+    counts
+}
+
+impl Model {
+    /// Train a model on messages with their tags.
+    pub fn train<'a>(examples: impl Iterator<Item = (&'a str, &'a TagSet)>) -> Model {
+        let examples: Vec<_> = examples
+            .map(|(content, tags)| (counts(content), tags))
+            .collect();
+        let mut model = Model {
+            messages: examples.len(),
+            ..Default::default()
+        };
+
+        let mut frequency: HashMap<&str, usize> = HashMap::new();
+        for (counts, _) in &examples {
+            for word in counts.keys() {
+                *frequency.entry(word).or_default() += 1;
+            }
+        }
+        let messages = model.messages as f64;
+        model.idf = frequency
+            .into_iter()
+            .map(|(word, n)| (word.to_string(), (messages / n as f64).ln() + 1.))
+            .collect();
+
+        for (counts, tags) in &examples {
+            let weights = model.weights(counts);
+            let learn = |class: &mut Class| {
+                class.messages += 1;
+                for (word, weight) in &weights {
+                    *class.words.entry(word.to_string()).or_default() += weight;
+                    class.total += weight;
+                }
+            };
+            if tags.is_empty() {
+                learn(&mut model.untagged);
+            }
+            for tag in tags.iter() {
+                learn(model.tags.entry(*tag).or_default());
+            }
+        }
+        model
+    }
+
+    /// TF-IDF weights of the words of a message. Words the model hasn't seen
+    /// are left out.
+    fn weights<'a>(&self, counts: &'a HashMap<String, usize>) -> Vec<(&'a str, f64)> {
+        counts
+            .iter()
+            .filter_map(|(word, n)| {
+                let idf = self.idf.get(word)?;
+                Some((word.as_str(), (1. + *n as f64).ln() * idf))
+            })
+            .collect()
+    }
+
+    /// Logarithm of how likely a class is to have a message with the given
+    /// word weights, up to a constant.
+    fn score(&self, class: &Class, weights: &[(&str, f64)]) -> f64 {
+        let classes = self.tags.len() + 1;
+        // Laplace smoothing, so that unseen words don't rule a class out.
+        let prior = (class.messages as f64 + 1.) / (self.messages + classes) as f64;
+        let total = class.total + self.idf.len() as f64;
+        weights.iter().fold(prior.ln(), |score, (word, weight)| {
+            let count = class.words.get(*word).copied().unwrap_or(0.);
+            score + weight * ((count + 1.) / total).ln()
+        })
+    }
+
+    /// The most likely tag of a message. `None` if it most likely has no
+    /// tags, or if it has no words the model knows.
+    pub fn suggest(&self, content: &str) -> Option<Suggestion> {
+        let counts = counts(content);
+        let weights = self.weights(&counts);
+        if weights.is_empty() {
+            return None;
+        }
+        let untagged = self.score(&self.untagged, &weights);
+        let scores: Vec<_> = self
+            .tags
+            .iter()
+            .map(|(tag, class)| (*tag, self.score(class, &weights)))
+            .collect();
+        let (tag, best) = scores.iter().copied().max_by(|a, b| a.1.total_cmp(&b.1))?;
+        if best < untagged {
+            return None;
+        }
+        // Softmax over all the classes, shifted by the best score so that
+        // the exponents don't underflow.
+        let sum: f64 = scores
+            .iter()
+            .map(|(_, score)| score)
+            .chain([&untagged])
+            .map(|score| (score - best).exp())
+            .sum();
+        Some(Suggestion {
+            tag,
+            confidence: (1. / sum) as f32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(id: u64) -> TagId {
+        serde_json::from_value(id.into()).unwrap()
+    }
+
+    fn train(examples: &[(&str, &TagSet)]) -> Model {
+        Model::train(examples.iter().copied())
+    }
+
+    #[test]
+    fn untagged_messages_outweigh_a_weak_tag() {
+        let bug = TagSet::from([tag(1)]);
+        let none = TagSet::new();
+        let model = train(&[
+            ("the build is broken again", &bug),
+            ("build broken after the merge", &bug),
+            ("who is up for lunch", &none),
+            ("lunch at noon", &none),
+            ("pizza for lunch today", &none),
+            ("lunch was great", &none),
+            ("the lunch place was closed", &bug),
+        ]);
+
+        assert!(model.suggest("lunch tomorrow?").is_none());
+        let suggestion = model.suggest("is the build broken?").unwrap();
+        assert_eq!(suggestion.tag, tag(1));
+    }
+
+    #[test]
+    fn unknown_words_give_nothing() {
+        let bug = TagSet::from([tag(1)]);
+        let model = train(&[("the build is broken", &bug)]);
+        assert!(model.suggest("zażółć gęślą jaźń").is_none());
+        assert!(model.suggest("").is_none());
+        assert!(Model::default().suggest("the build is broken").is_none());
+    }
+
+    #[test]
+    fn confidence_is_a_probability() {
+        let (bug, release) = (TagSet::from([tag(1)]), TagSet::from([tag(2)]));
+        let both = TagSet::from([tag(1), tag(2)]);
+        let model = train(&[
+            ("the build is broken", &bug),
+            ("crash on startup", &bug),
+            ("release notes for 1.2", &release),
+            ("tagging the release today", &release),
+            ("release build broken", &both),
+            ("good morning", &TagSet::new()),
+        ]);
+
+        let long = "build broken crash ".repeat(500);
+        for content in ["build", "release", "broken release", "crash crash", &long] {
+            if let Some(suggestion) = model.suggest(content) {
+                assert!(
+                    (0.0..=1.0).contains(&suggestion.confidence),
+                    "{}: {}",
+                    content,
+                    suggestion.confidence
+                );
+            }
+        }
+        // Long messages make the scores large, without the softmax under- or
+        // overflowing.
+        let sure = model.suggest(&long).unwrap();
+        assert_eq!(sure.tag, tag(1));
+        assert!(sure.confidence > 0.5);
+    }
+}
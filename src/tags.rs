@@ -0,0 +1,381 @@
+//! Tags the user marks messages with.
+//!
+//! A message can have any number of tags. Messages refer to tags by id, so
+//! renaming or recoloring a tag shows up on every message which has it, and
+//! deleting one takes it off its messages.
+//!
+//! Tags can be nested in other tags, e.g. `1.2` in `release`, which is
+//! written as `release/1.2`. Filtering by a tag includes the ones nested in it.
+//!
+//! Besides these private tags, every room has tags shared by its members,
+//! which the server keeps, see `SharedTags`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::protocol::{SharedTag, Tagged};
+
+/// Identifies a tag for as long as it exists, whatever its name and color.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(transparent)]
+pub struct TagId(u64);
+
+/// Tags of a message.
+pub type TagSet = BTreeSet<TagId>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub id: TagId,
+    pub name: String,
+    pub color: egui::Rgba,
+    /// Tag this one is nested in.
+    #[serde(default)]
+    pub parent: Option<TagId>,
+}
+
+/// All the user's tags, in the order they were created.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tags {
+    tags: Vec<Tag>,
+    /// Ids aren't reused, even after deleting a tag.
+    next_id: u64,
+}
+
+impl Default for Tags {
+    fn default() -> Self {
+        Tags {
+            tags: vec![],
+            next_id: 1,
+        }
+    }
+}
+
+impl Tags {
+    pub fn get(&self, id: TagId) -> Option<&Tag> {
+        self.tags.iter().find(|tag| tag.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: TagId) -> Option<&mut Tag> {
+        self.tags.iter_mut().find(|tag| tag.id == id)
+    }
+
+    /// The tag and the tags it's nested in, innermost first.
+    pub fn ancestors(&self, id: TagId) -> impl Iterator<Item = TagId> + '_ {
+        std::iter::successors(Some(id), move |id| self.get(*id).and_then(|tag| tag.parent))
+    }
+
+    /// Name of the tag following the names of the tags it's nested in, e.g.
+    /// `release/1.2`.
+    pub fn path(&self, id: TagId) -> String {
+        let mut names: Vec<_> = self
+            .ancestors(id)
+            .filter_map(|id| self.get(id))
+            .map(|tag| tag.name.as_str())
+            .collect();
+        names.reverse();
+        names.join("/")
+    }
+
+    /// Ids of all the tags with their depth, each one followed by the tags
+    /// nested in it.
+    pub fn tree(&self) -> Vec<(TagId, usize)> {
+        let mut tree = vec![];
+        self.subtree(None, 0, &mut tree);
+        tree
+    }
+
+    fn subtree(&self, parent: Option<TagId>, depth: usize, tree: &mut Vec<(TagId, usize)>) {
+        for tag in self.tags.iter().filter(|tag| tag.parent == parent) {
+            tree.push((tag.id, depth));
+            self.subtree(Some(tag.id), depth + 1, tree);
+        }
+    }
+
+    /// The tags with the given ids, in the order they were created.
+    pub fn of<'a>(&'a self, ids: &'a TagSet) -> impl Iterator<Item = &'a Tag> {
+        self.tags.iter().filter(move |tag| ids.contains(&tag.id))
+    }
+
+    pub fn add(&mut self, name: String, color: egui::Rgba, parent: Option<TagId>) -> TagId {
+        let id = TagId(self.next_id);
+        self.next_id += 1;
+        self.tags.push(Tag {
+            id,
+            name,
+            color,
+            parent,
+        });
+        id
+    }
+
+    /// Add a tag given by its path, e.g. `release/1.2`, creating the tags it's
+    /// nested in which don't exist yet. Returns `None` if the path has no
+    /// name in it.
+    pub fn add_path(&mut self, path: &str, color: egui::Rgba) -> Option<TagId> {
+        let names: Vec<_> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let (name, parents) = names.split_last()?;
+        let mut parent = None;
+        for name in parents {
+            let existing = self
+                .tags
+                .iter()
+                .find(|tag| tag.parent == parent && tag.name == *name);
+            parent = Some(match existing {
+                Some(tag) => tag.id,
+                None => self.add(name.to_string(), color, parent),
+            });
+        }
+        Some(self.add(name.to_string(), color, parent))
+    }
+
+    /// Nest a tag in another one, or with `None` take it out of its parent.
+    /// Returns whether it moved, which it can't into a tag nested in it.
+    pub fn set_parent(&mut self, id: TagId, parent: Option<TagId>) -> bool {
+        if let Some(parent) = parent {
+            if self.get(parent).is_none() || self.ancestors(parent).any(|a| a == id) {
+                return false;
+            }
+        }
+        match self.get_mut(id) {
+            Some(tag) => {
+                tag.parent = parent;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Delete a tag, the tags nested in it move up to its parent. Returns
+    /// whether it existed.
+    pub fn remove(&mut self, id: TagId) -> bool {
+        let index = match self.tags.iter().position(|tag| tag.id == id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let removed = self.tags.remove(index);
+        for tag in &mut self.tags {
+            if tag.parent == Some(id) {
+                tag.parent = removed.parent;
+            }
+        }
+        true
+    }
+}
+
+/// Add a tag to a set, or take it off.
+pub fn toggle(tags: &mut TagSet, id: TagId, on: bool) {
+    if on {
+        tags.insert(id);
+    } else {
+        tags.remove(&id);
+    }
+}
+
+/// Whether a message has to have any or all of the tags of a `Filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Match {
+    #[default]
+    Any,
+    All,
+}
+
+/// Picks messages by their tags.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub tags: TagSet,
+    pub mode: Match,
+}
+
+impl Filter {
+    /// Whether the filter lets every message through.
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Whether a message with the given tags passes. A message has a tag of
+    /// the filter if it has the tag or one nested in it.
+    pub fn matches(&self, all: &Tags, tags: &TagSet) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let tags: TagSet = tags.iter().flat_map(|id| all.ancestors(*id)).collect();
+        match self.mode {
+            Match::Any => !self.tags.is_disjoint(&tags),
+            Match::All => self.tags.is_subset(&tags),
+        }
+    }
+}
+
+/// Tags shared by the members of a room, as the server last described them.
+#[derive(Debug, Clone, Default)]
+pub struct SharedTags {
+    pub tags: Vec<SharedTag>,
+    pub tagged: Tagged,
+}
+
+impl SharedTags {
+    /// Whether the message with the given id has a tag.
+    pub fn has(&self, tag: u64, message: Option<u64>) -> bool {
+        message.is_some_and(|message| {
+            self.tagged
+                .get(&tag)
+                .is_some_and(|messages| messages.contains(&message))
+        })
+    }
+
+    /// Tags of the message with the given id.
+    pub fn of(&self, message: Option<u64>) -> impl Iterator<Item = &SharedTag> {
+        self.tags
+            .iter()
+            .filter(move |tag| self.has(tag.id, message))
+    }
+
+    /// Add a new tag, or replace the one with the same id.
+    pub fn put(&mut self, tag: SharedTag) {
+        match self.tags.iter_mut().find(|known| known.id == tag.id) {
+            Some(known) => *known = tag,
+            None => self.tags.push(tag),
+        }
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.tags.retain(|tag| tag.id != id);
+        self.tagged.remove(&id);
+    }
+
+    pub fn assign(&mut self, id: u64, messages: Vec<u64>, on: bool) {
+        let tagged = self.tagged.entry(id).or_default();
+        if on {
+            tagged.extend(messages);
+        } else {
+            for message in messages {
+                tagged.remove(&message);
+            }
+        }
+    }
+}
+
+/// Color of a shared tag.
+pub fn shared_color(tag: &SharedTag) -> egui::Rgba {
+    let [r, g, b, a] = tag.color;
+    egui::Rgba::from_rgba_premultiplied(r, g, b, a)
+}
+
+/// A color the way `SharedTag` has it.
+pub fn to_shared_color(color: egui::Rgba) -> [f32; 4] {
+    [color.r(), color.g(), color.b(), color.a()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(tags: &[TagId], mode: Match) -> Filter {
+        Filter {
+            tags: tags.iter().copied().collect(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn any_needs_one_tag_and_all_needs_every_one() {
+        let mut tags = Tags::default();
+        let bug = tags.add("bug".into(), egui::Rgba::RED, None);
+        let ui = tags.add("ui".into(), egui::Rgba::BLUE, None);
+        let docs = tags.add("docs".into(), egui::Rgba::GREEN, None);
+        let message = TagSet::from([bug, ui]);
+
+        assert!(filter(&[bug, docs], Match::Any).matches(&tags, &message));
+        assert!(!filter(&[docs], Match::Any).matches(&tags, &message));
+        assert!(filter(&[bug, ui], Match::All).matches(&tags, &message));
+        assert!(!filter(&[bug, docs], Match::All).matches(&tags, &message));
+        // An empty filter lets everything through, messages without tags too.
+        assert!(filter(&[], Match::All).matches(&tags, &TagSet::new()));
+        assert!(!filter(&[bug], Match::Any).matches(&tags, &TagSet::new()));
+    }
+
+    #[test]
+    fn toggling_adds_and_takes_off_one_tag() {
+        let mut tags = Tags::default();
+        let bug = tags.add("bug".into(), egui::Rgba::RED, None);
+        let ui = tags.add("ui".into(), egui::Rgba::BLUE, None);
+        let mut message = TagSet::from([ui]);
+
+        toggle(&mut message, bug, true);
+        toggle(&mut message, bug, true);
+        assert_eq!(message, TagSet::from([bug, ui]));
+        toggle(&mut message, ui, false);
+        toggle(&mut message, ui, false);
+        assert_eq!(message, TagSet::from([bug]));
+    }
+
+    #[test]
+    fn paths_reuse_the_tags_they_are_nested_in() {
+        let mut tags = Tags::default();
+        let old = tags.add_path("release/1.2", egui::Rgba::RED).unwrap();
+        let new = tags.add_path(" release / 1.3 ", egui::Rgba::RED).unwrap();
+        assert_eq!(tags.path(old), "release/1.2");
+        assert_eq!(tags.path(new), "release/1.3");
+        assert_eq!(tags.get(old).unwrap().parent, tags.get(new).unwrap().parent);
+        assert!(tags.add_path(" / ", egui::Rgba::RED).is_none());
+
+        let release = tags.get(old).unwrap().parent.unwrap();
+        assert_eq!(tags.tree(), [(release, 0), (old, 1), (new, 1)]);
+    }
+
+    #[test]
+    fn tags_cant_be_nested_in_themselves() {
+        let mut tags = Tags::default();
+        let a = tags.add("a".into(), egui::Rgba::RED, None);
+        let b = tags.add("b".into(), egui::Rgba::RED, Some(a));
+        let c = tags.add("c".into(), egui::Rgba::RED, Some(b));
+
+        assert!(!tags.set_parent(a, Some(a)));
+        assert!(!tags.set_parent(a, Some(c)));
+        assert!(!tags.set_parent(b, Some(TagId(99))));
+        assert_eq!(tags.get(a).unwrap().parent, None);
+
+        assert!(tags.set_parent(c, None));
+        assert!(tags.set_parent(a, Some(c)));
+        assert_eq!(tags.path(b), "c/a/b");
+    }
+
+    #[test]
+    fn removing_a_tag_moves_its_children_up() {
+        let mut tags = Tags::default();
+        let a = tags.add("a".into(), egui::Rgba::RED, None);
+        let b = tags.add("b".into(), egui::Rgba::RED, Some(a));
+        let c = tags.add("c".into(), egui::Rgba::RED, Some(b));
+        let d = tags.add("d".into(), egui::Rgba::RED, Some(b));
+
+        assert!(tags.remove(b));
+        assert!(!tags.remove(b));
+        assert_eq!(tags.tree(), [(a, 0), (c, 1), (d, 1)]);
+        assert_eq!(tags.path(d), "a/d");
+        // Ids of deleted tags aren't given again.
+        let e = tags.add("e".into(), egui::Rgba::RED, None);
+        assert_ne!(e, b);
+    }
+
+    #[test]
+    fn a_parent_matches_the_tags_nested_in_it() {
+        let mut tags = Tags::default();
+        let release = tags.add_path("release", egui::Rgba::RED).unwrap();
+        let old = tags.add_path("release/1.2", egui::Rgba::RED).unwrap();
+        let fix = tags.add_path("release/1.2/fix", egui::Rgba::RED).unwrap();
+        let bug = tags.add_path("bug", egui::Rgba::RED).unwrap();
+
+        let message = TagSet::from([fix]);
+        assert!(filter(&[release], Match::Any).matches(&tags, &message));
+        assert!(filter(&[release, old], Match::All).matches(&tags, &message));
+        assert!(!filter(&[release, bug], Match::All).matches(&tags, &message));
+        // A child doesn't match its parent.
+        assert!(!filter(&[fix], Match::Any).matches(&tags, &TagSet::from([old])));
+    }
+}
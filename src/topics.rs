@@ -0,0 +1,312 @@
+//! Finding where a conversation changes its topic, to propose fragments.
+//!
+//! Every gap between two messages gets a score from how much the words of
+//! the messages before it differ from the words of the messages after it
+//! (as in TextTiling), how long nobody wrote anything and whether other
+//! people took part in the conversation. The gaps with the highest scores
+//! split the room into topics.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::suggest::counts;
+use crate::tags::TagSet;
+
+/// How many messages on each side of a gap are compared.
+const WINDOW: usize = 3;
+/// The fewest messages a topic can have.
+const MIN_LEN: usize = 2;
+/// A pause after which the conversation changed its topic, in seconds.
+/// Shorter pauses only add to the score of their gap.
+const PAUSE: u64 = 30 * 60;
+/// The lowest score of a gap between topics, from 0 to 1, unless it's a
+/// pause of at least `PAUSE`.
+const THRESHOLD: f64 = 0.4;
+
+/// A message, as far as finding topics goes.
+pub struct Line<'a> {
+    pub id: u64,
+    pub content: &'a str,
+    pub sender: &'a str,
+    pub sent: Option<u64>,
+}
+
+/// A proposed fragment.
+#[derive(Debug, Clone)]
+pub struct Topic {
+    /// Id of the first message.
+    pub first: u64,
+    /// Id of the last message.
+    pub last: u64,
+    /// Words which stand out in the topic.
+    pub title: String,
+    pub tags: TagSet,
+    /// How many messages the topic has.
+    pub len: usize,
+}
+
+type Vector = HashMap<String, f64>;
+
+fn cosine(a: &Vector, b: &Vector) -> Option<f64> {
+    let norm = |v: &Vector| v.values().map(|x| x * x).sum::<f64>().sqrt();
+    let (na, nb) = (norm(a), norm(b));
+    if na == 0. || nb == 0. {
+        return None;
+    }
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(word, x)| Some(x * b.get(word)?))
+        .sum();
+    Some(dot / (na * nb))
+}
+
+/// Sum of the TF-IDF vectors of some messages.
+fn vector(counts: &[HashMap<String, usize>], idf: &HashMap<String, f64>) -> Vector {
+    let mut vector = Vector::new();
+    for counts in counts {
+        for (word, n) in counts {
+            *vector.entry(word.clone()).or_default() += (1. + *n as f64).ln() * idf[word];
+        }
+    }
+    vector
+}
+
+/// How much lower the similarity at a gap is than the highest ones around
+/// it, from 0 to 1.
+fn depths(similarity: &[Option<f64>]) -> Vec<f64> {
+    let value = |i: usize| similarity[i].unwrap_or(0.);
+    (0..similarity.len())
+        .map(|gap| {
+            if similarity[gap].is_none() {
+                return 0.;
+            }
+            // Climbing from the gap while the similarity keeps growing.
+            let mut left = value(gap);
+            for i in (0..gap).rev() {
+                if value(i) < left {
+                    break;
+                }
+                left = value(i);
+            }
+            let mut right = value(gap);
+            for i in gap + 1..similarity.len() {
+                if value(i) < right {
+                    break;
+                }
+                right = value(i);
+            }
+            ((left - value(gap)) + (right - value(gap))).min(1.)
+        })
+        .collect()
+}
+
+/// Split a room's messages into topics. Empty if it seems to have just one.
+pub fn topics(lines: &[Line<'_>]) -> Vec<Topic> {
+    if lines.len() < 2 * MIN_LEN {
+        return vec![];
+    }
+    let counts: Vec<_> = lines.iter().map(|line| counts(line.content)).collect();
+    let mut frequency: HashMap<&str, usize> = HashMap::new();
+    for counts in &counts {
+        for word in counts.keys() {
+            *frequency.entry(word).or_default() += 1;
+        }
+    }
+    let idf: HashMap<String, f64> = frequency
+        .into_iter()
+        .map(|(word, n)| (word.to_string(), (lines.len() as f64 / n as f64).ln()))
+        .collect();
+
+    // Gap `i` is between the messages `i` and `i + 1`.
+    let gaps = 0..lines.len() - 1;
+    let before = |gap: usize| gap.saturating_sub(WINDOW - 1)..gap + 1;
+    let after = |gap: usize| gap + 1..(gap + 1 + WINDOW).min(lines.len());
+    let similarity: Vec<_> = gaps
+        .clone()
+        .map(|gap| {
+            cosine(
+                &vector(&counts[before(gap)], &idf),
+                &vector(&counts[after(gap)], &idf),
+            )
+        })
+        .collect();
+    // Short messages have few words in common, so depths count compared to
+    // the deepest one, unless all of them are shallow.
+    let depths = depths(&similarity);
+    let deepest = depths.iter().copied().fold(0.5, f64::max);
+
+    let mut scores: Vec<(usize, f64)> = gaps
+        .filter_map(|gap| {
+            let pause = match (lines[gap].sent, lines[gap + 1].sent) {
+                (Some(a), Some(b)) => b.saturating_sub(a),
+                _ => 0,
+            };
+            let senders = |range: std::ops::Range<usize>| -> BTreeSet<&str> {
+                lines[range].iter().map(|line| line.sender).collect()
+            };
+            let (a, b) = (senders(before(gap)), senders(after(gap)));
+            let other = 1. - a.intersection(&b).count() as f64 / a.union(&b).count() as f64;
+            let score = 0.45 * depths[gap] / deepest
+                + 0.4 * (pause as f64 / PAUSE as f64).min(1.)
+                + 0.15 * other;
+            (pause >= PAUSE || score >= THRESHOLD).then_some((gap, score))
+        })
+        .collect();
+
+    // The best gaps first, as long as the topics around them aren't too
+    // short. A topic after gap `a` and up to gap `b` has `b - a` messages.
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    let mut cuts = BTreeSet::new();
+    for (gap, _) in scores {
+        let fits = |start: usize, end: usize| end - start >= MIN_LEN;
+        let previous = cuts.range(..gap).next_back().map_or(0, |cut| cut + 1);
+        let next = cuts.range(gap..).next().map_or(lines.len(), |cut| cut + 1);
+        if fits(previous, gap + 1) && fits(gap + 1, next) {
+            cuts.insert(gap);
+        }
+    }
+    if cuts.is_empty() {
+        return vec![];
+    }
+
+    let mut starts = vec![0];
+    starts.extend(cuts.iter().map(|cut| cut + 1));
+    let mut ends: Vec<_> = cuts.iter().map(|cut| cut + 1).collect();
+    ends.push(lines.len());
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(|(start, end)| Topic {
+            first: lines[start].id,
+            last: lines[end - 1].id,
+            title: title(&vector(&counts[start..end], &idf)),
+            tags: TagSet::new(),
+            len: end - start,
+        })
+        .collect()
+}
+
+/// The words with the highest weights.
+fn title(vector: &Vector) -> String {
+    let mut words: Vec<_> = vector.iter().filter(|(_, x)| **x > 0.).collect();
+    words.sort_by(|a, b| b.1.total_cmp(a.1).then(a.0.cmp(b.0)));
+    let words: Vec<_> = words
+        .into_iter()
+        .take(3)
+        .map(|(word, _)| word.as_str())
+        .collect();
+    if words.is_empty() {
+        "Topic".to_string()
+    } else {
+        words.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines numbered from 1, from `(sender, sent, content)`.
+    fn lines<'a>(messages: &[(&'a str, u64, &'a str)]) -> Vec<Line<'a>> {
+        messages
+            .iter()
+            .zip(1..)
+            .map(|(&(sender, sent, content), id)| Line {
+                id,
+                content,
+                sender,
+                sent: Some(sent),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn too_few_lines_give_no_topics() {
+        let lines = lines(&[
+            ("ala", 0, "the release is ready"),
+            ("ola", 7200, "who wants lunch"),
+            ("ala", 14400, "the build is broken"),
+        ]);
+        assert!(lines.len() < 2 * MIN_LEN);
+        assert!(topics(&lines).is_empty());
+    }
+
+    #[test]
+    fn a_long_pause_splits_topics() {
+        let lines = lines(&[
+            ("ala", 0, "the release is ready"),
+            ("ola", 60, "the release is ready"),
+            ("ala", 120, "the release is ready"),
+            ("ola", 120 + PAUSE, "the release is ready"),
+            ("ala", 180 + PAUSE, "the release is ready"),
+            ("ola", 240 + PAUSE, "the release is ready"),
+        ]);
+        let topics = topics(&lines);
+        let bounds: Vec<_> = topics
+            .iter()
+            .map(|topic| (topic.first, topic.last))
+            .collect();
+        assert_eq!(bounds, [(1, 3), (4, 6)]);
+    }
+
+    #[test]
+    fn a_shorter_pause_alone_doesnt_split_topics() {
+        let pause = PAUSE - 1;
+        let lines = lines(&[
+            ("ala", 0, "the release is ready"),
+            ("ola", 60, "the release is ready"),
+            ("ala", 120, "the release is ready"),
+            ("ola", 120 + pause, "the release is ready"),
+            ("ala", 180 + pause, "the release is ready"),
+            ("ola", 240 + pause, "the release is ready"),
+        ]);
+        assert!(topics(&lines).is_empty());
+    }
+
+    #[test]
+    fn topics_are_never_too_short() {
+        // Every gap is a long pause, but every topic needs `MIN_LEN` lines.
+        let messages: Vec<_> = (0..7)
+            .map(|i| ("ala", i * PAUSE, "the release is ready"))
+            .collect();
+        let lines = lines(&messages);
+        let topics = topics(&lines);
+        assert!(!topics.is_empty());
+        assert!(topics.iter().all(|topic| topic.len >= MIN_LEN));
+        // Together they cover every line, in order.
+        assert_eq!(topics.iter().map(|topic| topic.len).sum::<usize>(), 7);
+        assert_eq!(topics[0].first, 1);
+        assert_eq!(topics.last().unwrap().last, 7);
+        for pair in topics.windows(2) {
+            assert_eq!(pair[1].first, pair[0].last + 1);
+        }
+    }
+
+    #[test]
+    fn a_change_of_words_splits_topics() {
+        let lines = lines(&[
+            ("ala", 0, "pizza for lunch today?"),
+            ("ola", 60, "lunch today, pizza sounds good"),
+            ("ala", 120, "pizza lunch at noon today"),
+            ("ola", 180, "the release build failed"),
+            ("ala", 240, "release build failed again"),
+            ("ola", 300, "fixing the failed release build"),
+        ]);
+        let topics = topics(&lines);
+        let bounds: Vec<_> = topics
+            .iter()
+            .map(|topic| (topic.first, topic.last))
+            .collect();
+        assert_eq!(bounds, [(1, 3), (4, 6)]);
+    }
+
+    #[test]
+    fn a_uniform_conversation_gives_no_topics() {
+        let messages: Vec<_> = (0..10)
+            .map(|i| {
+                let sender = if i % 2 == 0 { "ala" } else { "ola" };
+                (sender, i * 60, "is the release ready yet")
+            })
+            .collect();
+        assert!(topics(&lines(&messages)).is_empty());
+    }
+}
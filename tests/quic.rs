@@ -0,0 +1,127 @@
+//! The QUIC transport over loopback, with the certificate the server
+//! generates.
+//!
+//!     cargo test --features quic --test quic
+
+#![cfg(feature = "quic")]
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tagchat::protocol::{self, ClientFrame, ServerFrame};
+use tagchat::server::config::{Config, Quic};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+const ROOM: &str = "quic";
+
+fn config(data_dir: &Path, listen: SocketAddr) -> Config {
+    Config {
+        bind: vec!["127.0.0.1:0".into()],
+        quic: Some(Quic { listen }),
+        data_dir: data_dir.to_path_buf(),
+        stats_interval: 0,
+        ..Default::default()
+    }
+}
+
+/// Connect once the server is up and has saved its certificate.
+async fn connect(addr: SocketAddr, data_dir: &Path) -> quinn::Connection {
+    let cert = data_dir.join("quic-cert.pem");
+    for _ in 0..50 {
+        if cert.exists() {
+            if let Ok(connection) = tagchat::connect_quic(addr, "localhost", &cert).await {
+                return connection;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("couldn't connect to the server over QUIC");
+}
+
+/// The next chat message from a stream, skipping the other frames.
+async fn message<R>(lines: &mut FramedRead<R, LinesCodec>) -> (u64, String)
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    loop {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next())
+            .await
+            .expect("no message in time")
+            .expect("the stream ended")
+            .unwrap();
+        if let Ok(ServerFrame::Message { id, content, .. }) = protocol::decode(&line) {
+            return (id, content);
+        }
+    }
+}
+
+#[tokio::test]
+async fn messages_and_history_over_quic() {
+    let data_dir: PathBuf =
+        std::env::temp_dir().join(format!("tagchat-quic-{}", std::process::id()));
+    let addr = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = {
+        let config = config(&data_dir, addr);
+        let reload = config.clone();
+        tokio::spawn(async move {
+            tagchat::server::run(config, move || Ok(reload.clone()), async {
+                let _ = stopped.await;
+            })
+            .await
+            .unwrap()
+        })
+    };
+
+    // A live message comes on the stream the client opened.
+    let alice = connect(addr, &data_dir).await;
+    let (write, read) = alice.open_bi().await.unwrap();
+    let mut write = FramedWrite::new(write, LinesCodec::new());
+    let mut live = FramedRead::new(read, LinesCodec::new());
+    for frame in [
+        ClientFrame::Login {
+            name: "alice".into(),
+        },
+        ClientFrame::Message {
+            room: ROOM.into(),
+            content: "over quic".into(),
+        },
+    ] {
+        write.send(protocol::encode(&frame)).await.unwrap();
+    }
+    let (id, content) = message(&mut live).await;
+    assert_eq!(content, "over quic");
+
+    // The same message, replayed on joining, comes on the server's stream.
+    let bob = connect(addr, &data_dir).await;
+    let (write, _read) = bob.open_bi().await.unwrap();
+    let mut write = FramedWrite::new(write, LinesCodec::new());
+    for frame in [
+        ClientFrame::Login { name: "bob".into() },
+        ClientFrame::Join {
+            room: ROOM.into(),
+            since: None,
+        },
+    ] {
+        write.send(protocol::encode(&frame)).await.unwrap();
+    }
+    let history = tokio::time::timeout(Duration::from_secs(5), bob.accept_uni())
+        .await
+        .expect("no history stream in time")
+        .unwrap();
+    let mut history = FramedRead::new(history, LinesCodec::new());
+    assert_eq!(message(&mut history).await, (id, "over quic".to_string()));
+
+    for connection in [alice, bob] {
+        connection.close(0u32.into(), b"done");
+    }
+    stop.send(()).unwrap();
+    server.await.unwrap();
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}